@@ -1,5 +1,12 @@
 use std::path::Path;
 
+use crate::backup::models::chunker_parameters::ChunkerParameters;
+use crate::backup::models::compression::Compression;
+use crate::backup::models::repository_config::ChunkIdScheme;
+use crate::backup::models::repository_key::RepositoryKey;
+use crate::backup::models::storage_url::StorageUrl;
+use crate::backup::models::zstd_dictionary::ZstdDictionary;
+use anyhow::{Error, Result};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -7,21 +14,80 @@ pub struct BackupConfig {
     pub average_size: u32,
     pub input_path: String,
     pub output_path: String,
+    // set for encrypted repositories
+    #[serde(skip)]
+    pub repository_key: Option<RepositoryKey>,
+    pub chunk_id_scheme: ChunkIdScheme,
+    pub min_size: u32,
+    pub max_size: u32,
+    // chunks are stored in the repository directory if not set
+    #[serde(skip)]
+    pub storage_url: Option<StorageUrl>,
+    #[serde(default)]
+    pub compression: Compression,
+    // all zstd dictionaries of the repository, chunks name the one they need
+    #[serde(skip)]
+    pub dictionaries: Vec<ZstdDictionary>,
+    // the dictionary written chunks are compressed with
+    #[serde(skip)]
+    pub dictionary_id: Option<u32>,
 }
 
 impl BackupConfig {
     pub fn new(average_size: u32, input_path: &Path, output_path: &Path) -> BackupConfig {
+        let chunker_parameters = ChunkerParameters::new(average_size);
         BackupConfig {
             average_size,
             input_path: input_path.display().to_string(),
             output_path: output_path.display().to_string(),
+            repository_key: None,
+            chunk_id_scheme: ChunkIdScheme::default(),
+            min_size: chunker_parameters.min_size,
+            max_size: chunker_parameters.max_size,
+            storage_url: None,
+            compression: Compression::default(),
+            dictionaries: Vec::new(),
+            dictionary_id: None,
         }
     }
-    pub fn min_size(&self) -> u32 {
-        self.average_size / 4
+
+    pub fn chunk_id_key(&self) -> Result<Option<[u8; 32]>> {
+        match self.chunk_id_scheme {
+            ChunkIdScheme::Blake3 => Ok(None),
+            ChunkIdScheme::KeyedBlake3 => match &self.repository_key {
+                Some(repository_key) => Ok(Some(repository_key.chunk_id_key())),
+                None => Err(Error::msg(
+                    "The repository uses keyed chunk ids, they require the repository key",
+                )),
+            },
+        }
+    }
+
+    pub fn chunker_parameters(&self) -> ChunkerParameters {
+        ChunkerParameters {
+            min_size: self.min_size,
+            average_size: self.average_size,
+            max_size: self.max_size,
+        }
     }
 
-    pub fn max_size(&self) -> u32 {
-        self.average_size / 4
+    pub fn set_chunker_parameters(&mut self, chunker_parameters: ChunkerParameters) {
+        self.min_size = chunker_parameters.min_size;
+        self.average_size = chunker_parameters.average_size;
+        self.max_size = chunker_parameters.max_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyed_chunk_id_without_repository_key() {
+        let mut backup_config = BackupConfig::new(1024, Path::new("input"), Path::new("output"));
+        assert_eq!(backup_config.chunk_id_key().unwrap(), None);
+
+        backup_config.chunk_id_scheme = ChunkIdScheme::KeyedBlake3;
+        assert!(backup_config.chunk_id_key().is_err());
     }
 }
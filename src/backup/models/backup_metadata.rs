@@ -1,14 +1,22 @@
+use crate::backup::models::directory::Directory;
+use crate::backup::models::extended_attributes::ExtendedAttributesMap;
 use crate::backup::models::file_metadata::FileMetadata;
+use crate::backup::models::hardlink_group::HardlinkGroup;
+use crate::backup::models::lib::{deserialize_from_file, serialize_to_new_file};
+use crate::backup::models::repository_key::RepositoryKey;
+use crate::backup::models::snapshot::Snapshot;
+use crate::backup::models::special_file::SpecialFile;
 use crate::backup::models::symlink::Symlink;
 use crate::backup::services::chunk_storage::ChunkMap;
 use anyhow::{Error, Result};
-use log::debug;
+use chrono::{DateTime, Local};
+use itertools::Itertools;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{BufReader, Write},
-    path::Path,
+    fs::{self},
+    path::{Path, PathBuf},
 };
 
 pub type FileMetadataMap = HashMap<String, FileMetadata>;
@@ -20,84 +28,294 @@ pub enum SerializationType {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BackupMetadata {
-    pub chunk_map: ChunkMap,
+    pub snapshot: Snapshot,
     // file_path -> FileMetadata
     pub file_metadata_map: FileMetadataMap,
     pub symlinks: Vec<Symlink>,
+    // missing in snapshots written before directories were backed up
+    #[serde(default)]
+    pub directories: Vec<Directory>,
+    #[serde(default)]
+    pub hardlink_groups: Vec<HardlinkGroup>,
+    #[serde(default)]
+    pub extended_attributes: ExtendedAttributesMap,
+    #[serde(default)]
+    pub special_files: Vec<SpecialFile>,
+}
+
+// the single metadata file written before snapshots existed
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LegacyBackupMetadata {
+    pub chunk_map: ChunkMap,
+    pub file_metadata_map: FileMetadataMap,
+    pub symlinks: Vec<Symlink>,
+}
+
+impl LegacyBackupMetadata {
+    pub(crate) fn deserialize(directory_path: &Path) -> Result<Option<LegacyBackupMetadata>> {
+        let path = directory_path.join(BackupMetadata::LEGACY_BACKUP_METADATA_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        // written before encryption existed, never encrypted
+        Ok(Some(deserialize_from_file(&path, None, &[])?))
+    }
 }
 
 impl BackupMetadata {
-    const BACKUP_METADATA_FILE: &'static str = "metadata";
+    const SNAPSHOTS_DIRECTORY: &'static str = "snapshots";
+    // the snapshot without its files, so snapshots can be listed without reading them
+    const SNAPSHOT_HEADERS_DIRECTORY: &'static str = "snapshot_headers";
+    const LEGACY_BACKUP_METADATA_FILE: &'static str = "metadata";
+    pub const LEGACY_SNAPSHOT_ID: &'static str = "legacy";
 
-    pub fn new() -> BackupMetadata {
+    pub fn new(snapshot: Snapshot) -> BackupMetadata {
         BackupMetadata {
-            chunk_map: Default::default(),
+            snapshot,
             file_metadata_map: Default::default(),
             symlinks: Default::default(),
+            directories: Default::default(),
+            hardlink_groups: Default::default(),
+            extended_attributes: Default::default(),
+            special_files: Default::default(),
         }
     }
 
     pub fn new_with_data(
-        chunk_map: ChunkMap,
+        snapshot: Snapshot,
         file_metadata_map: FileMetadataMap,
         symlinks: Vec<Symlink>,
+        directories: Vec<Directory>,
+        hardlink_groups: Vec<HardlinkGroup>,
+        extended_attributes: ExtendedAttributesMap,
+        special_files: Vec<SpecialFile>,
     ) -> BackupMetadata {
         BackupMetadata {
-            chunk_map,
+            snapshot,
             file_metadata_map,
             symlinks,
+            directories,
+            hardlink_groups,
+            extended_attributes,
+            special_files,
+        }
+    }
+
+    pub fn snapshots_path(directory_path: &Path) -> PathBuf {
+        directory_path.join(Self::SNAPSHOTS_DIRECTORY)
+    }
+
+    pub fn snapshot_path(directory_path: &Path, snapshot_id: &str) -> PathBuf {
+        if snapshot_id == Self::LEGACY_SNAPSHOT_ID {
+            return directory_path.join(Self::LEGACY_BACKUP_METADATA_FILE);
         }
+        Self::snapshots_path(directory_path).join(snapshot_id)
+    }
+
+    fn snapshot_header_path(directory_path: &Path, snapshot_id: &str) -> PathBuf {
+        directory_path
+            .join(Self::SNAPSHOT_HEADERS_DIRECTORY)
+            .join(snapshot_id)
     }
 
     pub fn serialize(
         &self,
         directory_path: &Path,
         serialization_type: SerializationType,
+        repository_key: Option<&RepositoryKey>,
     ) -> Result<()> {
-        fs::create_dir_all(directory_path)?;
-        let mut file = fs::File::create(directory_path.join(Self::BACKUP_METADATA_FILE))?;
-        let bytes: Vec<u8>;
-
-        match serialization_type {
-            SerializationType::JSON => {
-                let json_data = serde_json::to_vec(&self)?;
-                bytes = json_data.clone()
-            }
-            SerializationType::MessagePack => {
-                let message_pack_data = rmp_serde::to_vec(&self)?;
-                bytes = message_pack_data.clone()
-            }
+        fs::create_dir_all(Self::snapshots_path(directory_path))?;
+        serialize_to_new_file(
+            &Self::snapshot_path(directory_path, &self.snapshot.id),
+            self,
+            serialization_type,
+            repository_key,
+            format!("snapshot:{}", self.snapshot.id).as_bytes(),
+        )?;
+        fs::create_dir_all(directory_path.join(Self::SNAPSHOT_HEADERS_DIRECTORY))?;
+        serialize_to_new_file(
+            &Self::snapshot_header_path(directory_path, &self.snapshot.id),
+            &self.snapshot,
+            SerializationType::MessagePack,
+            repository_key,
+            format!("snapshot-header:{}", self.snapshot.id).as_bytes(),
+        )
+    }
+
+    pub fn deserialize(
+        directory_path: &Path,
+        snapshot_id: &str,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<BackupMetadata> {
+        if snapshot_id == Self::LEGACY_SNAPSHOT_ID {
+            return Self::deserialize_legacy(directory_path)?
+                .ok_or_else(|| Error::msg("Could not find legacy backup metadata"));
         }
+        deserialize_from_file(
+            &Self::snapshot_path(directory_path, snapshot_id),
+            repository_key,
+            format!("snapshot:{}", snapshot_id).as_bytes(),
+        )
+    }
+
+    fn deserialize_legacy(directory_path: &Path) -> Result<Option<BackupMetadata>> {
+        let legacy_backup_metadata = match LegacyBackupMetadata::deserialize(directory_path)? {
+            Some(legacy_backup_metadata) => legacy_backup_metadata,
+            None => return Ok(None),
+        };
+        let modified =
+            fs::metadata(directory_path.join(Self::LEGACY_BACKUP_METADATA_FILE))?.modified()?;
 
-        Ok(file.write_all(bytes.as_slice())?)
+        Ok(Some(BackupMetadata::new_with_data(
+            Snapshot {
+                id: Self::LEGACY_SNAPSHOT_ID.to_string(),
+                time: DateTime::<Local>::from(modified),
+                hostname: Default::default(),
+                source_path: Default::default(),
+            },
+            legacy_backup_metadata.file_metadata_map,
+            legacy_backup_metadata.symlinks,
+            Vec::new(),
+            Vec::new(),
+            Default::default(),
+            Vec::new(),
+        )))
     }
 
-    pub fn deserialize(directory_path: &Path) -> Result<BackupMetadata> {
-        let path = directory_path.join(Self::BACKUP_METADATA_FILE);
+    pub fn snapshot_ids(directory_path: &Path) -> Result<Vec<String>> {
+        let mut snapshot_ids = Vec::new();
+        if directory_path
+            .join(Self::LEGACY_BACKUP_METADATA_FILE)
+            .exists()
+        {
+            snapshot_ids.push(Self::LEGACY_SNAPSHOT_ID.to_string());
+        }
 
-        if !path.exists() {
-            return Ok(BackupMetadata::new());
+        let snapshots_path = Self::snapshots_path(directory_path);
+        if !snapshots_path.exists() {
+            return Ok(snapshot_ids);
         }
 
-        debug!("Trying deserialize as json");
-        let json_result = serde_json::from_reader::<BufReader<File>, BackupMetadata>(
-            BufReader::new(File::open(path.clone())?),
-        );
-        if json_result.is_ok() {
-            return Ok(json_result?);
+        for dir_entry_result in fs::read_dir(snapshots_path)? {
+            let dir_entry = dir_entry_result?;
+            let file_name = dir_entry.file_name().to_string_lossy().to_string();
+            // skip unfinished writes
+            if file_name.ends_with(".tmp") {
+                continue;
+            }
+            snapshot_ids.push(file_name);
         }
+        Ok(snapshot_ids)
+    }
 
-        debug!("Trying deserialize as messagepack");
-        let msgpck_result = rmp_serde::from_read::<BufReader<File>, BackupMetadata>(
-            BufReader::new(File::open(path.clone())?),
-        );
-        if msgpck_result.is_ok() {
-            return Ok(msgpck_result?);
+    // reads the header of a snapshot, snapshots written before headers existed are read completely
+    pub fn deserialize_snapshot(
+        directory_path: &Path,
+        snapshot_id: &str,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<Snapshot> {
+        let snapshot_header_path = Self::snapshot_header_path(directory_path, snapshot_id);
+        if snapshot_id == Self::LEGACY_SNAPSHOT_ID || !snapshot_header_path.exists() {
+            return Ok(Self::deserialize(directory_path, snapshot_id, repository_key)?.snapshot);
         }
+        deserialize_from_file(
+            &snapshot_header_path,
+            repository_key,
+            format!("snapshot-header:{}", snapshot_id).as_bytes(),
+        )
+    }
 
-        Err(Error::msg(
-            "Could not deserialize backup metadata".to_string(),
-        ))
+    // all snapshots of the repository without their files, oldest first
+    pub fn snapshots(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<Vec<Snapshot>> {
+        Ok(Self::snapshot_ids(directory_path)?
+            .iter()
+            .map(|snapshot_id| {
+                Self::deserialize_snapshot(directory_path, snapshot_id, repository_key)
+            })
+            .collect::<Result<Vec<Snapshot>>>()?
+            .into_iter()
+            .sorted_by(|a, b| Ord::cmp(&a.time, &b.time))
+            .collect())
+    }
+
+    // all snapshots of the repository, oldest first
+    pub fn list(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<Vec<BackupMetadata>> {
+        Ok(Self::snapshot_ids(directory_path)?
+            .iter()
+            .map(|snapshot_id| Self::deserialize(directory_path, snapshot_id, repository_key))
+            .collect::<Result<Vec<BackupMetadata>>>()?
+            .into_iter()
+            .sorted_by(|a, b| Ord::cmp(&a.snapshot.time, &b.snapshot.time))
+            .collect())
+    }
+
+    pub fn latest(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<Option<BackupMetadata>> {
+        Self::latest_matching(directory_path, repository_key, |_| true)
+    }
+
+    // only the headers are read to find the latest matching snapshot,
+    // snapshots that cannot be read are skipped
+    pub fn latest_matching(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+        matches: impl Fn(&Snapshot) -> bool,
+    ) -> Result<Option<BackupMetadata>> {
+        let latest_snapshot = Self::snapshot_ids(directory_path)?
+            .iter()
+            .filter_map(|snapshot_id| {
+                match Self::deserialize_snapshot(directory_path, snapshot_id, repository_key) {
+                    Ok(snapshot) => Some(snapshot),
+                    Err(err) => {
+                        warn!("Skipping snapshot {}: {}", snapshot_id, err);
+                        None
+                    }
+                }
+            })
+            .filter(|snapshot| matches(snapshot))
+            .max_by(|a, b| Ord::cmp(&a.time, &b.time));
+
+        latest_snapshot
+            .map(|snapshot| Self::deserialize(directory_path, &snapshot.id, repository_key))
+            .transpose()
+    }
+
+    // finds a snapshot by its id or an unambiguous id prefix
+    pub fn find(
+        directory_path: &Path,
+        snapshot_id: &str,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<BackupMetadata> {
+        let matching_ids: Vec<String> = Self::snapshot_ids(directory_path)?
+            .into_iter()
+            .filter(|id| id.starts_with(snapshot_id))
+            .collect();
+
+        match matching_ids.as_slice() {
+            [id] => Self::deserialize(directory_path, id, repository_key),
+            [] => Err(Error::msg(format!("Snapshot {} not found", snapshot_id))),
+            _ => Err(Error::msg(format!(
+                "Snapshot id {} is ambiguous",
+                snapshot_id
+            ))),
+        }
+    }
+
+    pub fn remove(directory_path: &Path, snapshot_id: &str) -> Result<()> {
+        fs::remove_file(Self::snapshot_path(directory_path, snapshot_id))?;
+        let snapshot_header_path = Self::snapshot_header_path(directory_path, snapshot_id);
+        if snapshot_header_path.exists() {
+            fs::remove_file(snapshot_header_path)?;
+        }
+        Ok(())
     }
 
     pub fn insert_symlink(&mut self, symlink: Symlink) {
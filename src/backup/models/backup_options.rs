@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    // gitignore style patterns, relative to the input path
+    pub excludes: Vec<String>,
+    // re-include paths matched by an exclude pattern
+    pub includes: Vec<String>,
+    // files with one exclude pattern per line
+    pub exclude_files: Vec<PathBuf>,
+    // directories containing one of these file names are excluded
+    pub exclude_if_present: Vec<String>,
+    // read every file, even if it did not change since the parent snapshot
+    pub force_rehash: bool,
+    // threads chunking files, None or 0 uses one per cpu
+    pub workers: Option<usize>,
+}
@@ -8,11 +8,26 @@ pub struct Chunk {
     pub length: usize,
 }
 
-impl From<&ChunkData> for Chunk {
-    fn from(chunk_data: &ChunkData) -> Self {
+impl Chunk {
+    // chunk_id_key hides the content hash, so stored chunks cannot be
+    // compared against hashes of known files
+    pub fn new(chunk_data: &ChunkData, chunk_id_key: Option<&[u8; 32]>) -> Chunk {
         Chunk {
-            hash: blake3::hash(&chunk_data.data).to_hex().to_string(),
+            hash: Self::hash(&chunk_data.data, chunk_id_key),
             length: chunk_data.length,
         }
     }
+
+    pub fn hash(data: &[u8], chunk_id_key: Option<&[u8; 32]>) -> String {
+        match chunk_id_key {
+            Some(chunk_id_key) => blake3::keyed_hash(chunk_id_key, data).to_hex().to_string(),
+            None => blake3::hash(data).to_hex().to_string(),
+        }
+    }
+}
+
+impl From<&ChunkData> for Chunk {
+    fn from(chunk_data: &ChunkData) -> Self {
+        Chunk::new(chunk_data, None)
+    }
 }
@@ -0,0 +1,152 @@
+use crate::backup::models::compression::Compression;
+use anyhow::{Error, Result};
+
+// written in front of the compressed data of every chunk, inside the encryption,
+// chunks stored without it are plain zstd frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub compression_id: u8,
+    pub flags: u8,
+    // the zstd dictionary the data was compressed with, 0 for none
+    pub dictionary_id: u32,
+}
+
+impl ChunkHeader {
+    // cannot be confused with the zstd frame magic 28 b5 2f fd
+    const MAGIC: [u8; 2] = *b"HC";
+    // version 1 headers have no flags, version 2 headers no dictionary id
+    const VERSION: u8 = 3;
+    const SIZE: usize = 9;
+    const VERSION_1_SIZE: usize = 4;
+    const VERSION_2_SIZE: usize = 5;
+
+    // compression was skipped for incompressible data, the data is stored raw
+    pub const STORED_RAW: u8 = 1;
+
+    pub fn new(compression: &Compression) -> ChunkHeader {
+        ChunkHeader {
+            compression_id: compression.id(),
+            flags: 0,
+            dictionary_id: 0,
+        }
+    }
+
+    pub fn with_dictionary(dictionary_id: u32) -> ChunkHeader {
+        ChunkHeader {
+            dictionary_id,
+            ..ChunkHeader::new(&Compression::Zstd { level: 1 })
+        }
+    }
+
+    pub fn stored_raw() -> ChunkHeader {
+        ChunkHeader {
+            compression_id: Compression::None.id(),
+            flags: Self::STORED_RAW,
+            dictionary_id: 0,
+        }
+    }
+
+    pub fn is_stored_raw(&self) -> bool {
+        self.flags & Self::STORED_RAW != 0
+    }
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut header = [0; Self::SIZE];
+        header[..2].copy_from_slice(&Self::MAGIC);
+        header[2] = Self::VERSION;
+        header[3] = self.compression_id;
+        header[4] = self.flags;
+        header[5..].copy_from_slice(&self.dictionary_id.to_le_bytes());
+        header
+    }
+
+    // the header and the compressed data following it
+    pub fn decode(data: &[u8]) -> Result<(ChunkHeader, &[u8])> {
+        if !data.starts_with(&Self::MAGIC) {
+            return Ok((ChunkHeader::new(&Compression::Zstd { level: 1 }), data));
+        }
+        let size = match data.get(2) {
+            Some(1) => Self::VERSION_1_SIZE,
+            Some(2) => Self::VERSION_2_SIZE,
+            Some(&Self::VERSION) => Self::SIZE,
+            Some(version) => {
+                return Err(Error::msg(format!(
+                    "Unknown chunk header version {}",
+                    version
+                )))
+            }
+            None => Self::SIZE,
+        };
+        if data.len() < size {
+            return Err(Error::msg("Chunk header is truncated"));
+        }
+        Ok((
+            ChunkHeader {
+                compression_id: data[3],
+                flags: if size > Self::VERSION_1_SIZE {
+                    data[4]
+                } else {
+                    0
+                },
+                dictionary_id: if size == Self::SIZE {
+                    u32::from_le_bytes(data[5..Self::SIZE].try_into()?)
+                } else {
+                    0
+                },
+            },
+            &data[size..],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_header_decode() {
+        let mut data = ChunkHeader::new(&Compression::Lz4).encode().to_vec();
+        data.extend(b"compressed");
+        let (chunk_header, compressed_data) = ChunkHeader::decode(&data).unwrap();
+        assert_eq!(chunk_header.compression_id, Compression::Lz4.id());
+        assert!(!chunk_header.is_stored_raw());
+        assert_eq!(compressed_data, b"compressed");
+
+        let mut data = ChunkHeader::stored_raw().encode().to_vec();
+        data.extend(b"raw");
+        let (chunk_header, raw_data) = ChunkHeader::decode(&data).unwrap();
+        assert!(chunk_header.is_stored_raw());
+        assert_eq!(chunk_header.compression_id, Compression::None.id());
+        assert_eq!(raw_data, b"raw");
+
+        let mut data = ChunkHeader::with_dictionary(258).encode().to_vec();
+        data.extend(b"zstd");
+        let (chunk_header, compressed_data) = ChunkHeader::decode(&data).unwrap();
+        assert_eq!(chunk_header.dictionary_id, 258);
+        assert_eq!(
+            chunk_header.compression_id,
+            Compression::Zstd { level: 1 }.id()
+        );
+        assert_eq!(compressed_data, b"zstd");
+
+        // version 1 and 2 headers
+        let (chunk_header, compressed_data) = ChunkHeader::decode(b"HC\x01\x02lz4").unwrap();
+        assert_eq!(chunk_header, ChunkHeader::new(&Compression::Lz4));
+        assert_eq!(compressed_data, b"lz4");
+        let (chunk_header, raw_data) = ChunkHeader::decode(b"HC\x02\x00\x01raw").unwrap();
+        assert_eq!(chunk_header, ChunkHeader::stored_raw());
+        assert_eq!(raw_data, b"raw");
+
+        // chunks written before the header existed
+        let zstd_data = zstd::encode_all(&b"legacy"[..], 1).unwrap();
+        let (chunk_header, compressed_data) = ChunkHeader::decode(&zstd_data).unwrap();
+        assert_eq!(
+            chunk_header.compression_id,
+            Compression::Zstd { level: 1 }.id()
+        );
+        assert_eq!(compressed_data, zstd_data.as_slice());
+
+        assert!(ChunkHeader::decode(b"HC\x09\x01").is_err());
+        assert!(ChunkHeader::decode(b"HC\x03\x01\x00\x01").is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use crate::backup::models::backup_metadata::{LegacyBackupMetadata, SerializationType};
+use crate::backup::models::lib::{deserialize_from_file, serialize_to_file};
+use crate::backup::models::repository_key::RepositoryKey;
+use crate::backup::services::chunk_storage::ChunkMap;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// the chunk map shared by all snapshots of a repository
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChunkIndex {
+    pub chunk_map: ChunkMap,
+}
+
+impl ChunkIndex {
+    const CHUNK_INDEX_FILE: &'static str = "index";
+
+    pub fn new(chunk_map: ChunkMap) -> ChunkIndex {
+        ChunkIndex { chunk_map }
+    }
+
+    pub fn serialize(
+        &self,
+        directory_path: &Path,
+        serialization_type: SerializationType,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<()> {
+        fs::create_dir_all(directory_path)?;
+        serialize_to_file(
+            &directory_path.join(Self::CHUNK_INDEX_FILE),
+            self,
+            serialization_type,
+            repository_key,
+            Self::CHUNK_INDEX_FILE.as_bytes(),
+        )
+    }
+
+    pub fn deserialize(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<ChunkIndex> {
+        let path = directory_path.join(Self::CHUNK_INDEX_FILE);
+        if path.exists() {
+            return deserialize_from_file(&path, repository_key, Self::CHUNK_INDEX_FILE.as_bytes());
+        }
+
+        // repositories written before snapshots kept the chunk map in the metadata file
+        match LegacyBackupMetadata::deserialize(directory_path)? {
+            Some(legacy_backup_metadata) => Ok(ChunkIndex::new(legacy_backup_metadata.chunk_map)),
+            None => Ok(ChunkIndex::default()),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Error, Result};
+use fastcdc::v2020::{
+    AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// chunks of data backed up with different parameters are not deduplicated
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkerParameters {
+    pub min_size: u32,
+    pub average_size: u32,
+    pub max_size: u32,
+}
+
+impl fmt::Display for ChunkerParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "min {} / avg {} / max {} bytes",
+            self.min_size, self.average_size, self.max_size
+        )
+    }
+}
+
+impl ChunkerParameters {
+    // a quarter and four times the average size, as recommended by FastCDC
+    pub fn new(average_size: u32) -> ChunkerParameters {
+        ChunkerParameters {
+            min_size: average_size / 4,
+            average_size,
+            max_size: average_size.saturating_mul(4),
+        }
+    }
+
+    fn validate_size(name: &str, size: u32, min: u32, max: u32) -> Result<()> {
+        if size < min || size > max {
+            return Err(Error::msg(format!(
+                "The {} chunk size {} is not between {} and {}",
+                name, size, min, max
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        Self::validate_size("minimum", self.min_size, MINIMUM_MIN, MINIMUM_MAX)?;
+        Self::validate_size("average", self.average_size, AVERAGE_MIN, AVERAGE_MAX)?;
+        Self::validate_size("maximum", self.max_size, MAXIMUM_MIN, MAXIMUM_MAX)?;
+        if self.min_size > self.average_size || self.average_size > self.max_size {
+            return Err(Error::msg(format!(
+                "The chunk sizes must be ordered minimum <= average <= maximum, got {}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunker_parameters_new_valid() {
+        for average_size in [AVERAGE_MIN, 4096, 1024 * 1024, AVERAGE_MAX] {
+            let chunker_parameters = ChunkerParameters::new(average_size);
+            assert!(chunker_parameters.validate().is_ok());
+            assert!(chunker_parameters.min_size < chunker_parameters.max_size);
+        }
+    }
+
+    #[test]
+    fn chunker_parameters_validate() {
+        let chunker_parameters = ChunkerParameters::new(4096);
+        assert!(ChunkerParameters {
+            average_size: AVERAGE_MAX * 2,
+            ..chunker_parameters
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkerParameters {
+            min_size: 32,
+            ..chunker_parameters
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkerParameters {
+            min_size: 8192,
+            ..chunker_parameters
+        }
+        .validate()
+        .is_err());
+        assert!(ChunkerParameters {
+            max_size: 2048,
+            ..chunker_parameters
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::backup::models::compression::Compression;
+use anyhow::Result;
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// why a chunk is stored without compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    Extension,
+    Magic,
+    Entropy,
+    NotSmaller,
+}
+
+// formats that are compressed already, lowercase
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic",
+    "jar", "jpeg", "jpg", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus",
+    "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+// (offset, bytes) at the start of files in the formats above
+const INCOMPRESSIBLE_MAGIC_BYTES: &[(usize, &[u8])] = &[
+    (0, b"\xff\xd8\xff"),       // jpeg
+    (0, b"\x89PNG"),            // png
+    (0, b"GIF8"),               // gif
+    (8, b"WEBP"),               // webp
+    (4, b"ftyp"),               // mp4, mov, heic, avif
+    (0, b"\x1a\x45\xdf\xa3"),   // mkv, webm
+    (0, b"OggS"),               // ogg, opus
+    (0, b"fLaC"),               // flac
+    (0, b"ID3"),                // mp3
+    (0, b"PK\x03\x04"),         // zip, jar, docx
+    (0, b"\x1f\x8b"),           // gzip
+    (0, b"BZh"),                // bzip2
+    (0, b"\xfd7zXZ\x00"),       // xz
+    (0, b"\x28\xb5\x2f\xfd"),   // zstd
+    (0, b"7z\xbc\xaf\x27\x1c"), // 7z
+    (0, b"Rar!"),               // rar
+];
+
+// bytes needed to match all magic bytes
+pub const MAGIC_BYTES_SIZE: usize = 12;
+
+// only the start of a chunk is sampled
+const SAMPLE_SIZE: usize = 64 * 1024;
+// in bits per byte, 8 is random data, text is around 4.5
+const ENTROPY_THRESHOLD: f64 = 7.5;
+// a trial compression of the sample has to save at least 3%
+const MIN_TRIAL_RATIO: f64 = 0.97;
+
+// checked once per file, before any chunk is compressed
+pub fn file_skip_reason(file_path: &Path, head: &[u8]) -> Option<SkipReason> {
+    let extension = file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    if extension.is_some_and(|extension| INCOMPRESSIBLE_EXTENSIONS.contains(&extension.as_str())) {
+        return Some(SkipReason::Extension);
+    }
+    if INCOMPRESSIBLE_MAGIC_BYTES.iter().any(|(offset, magic)| {
+        head.get(*offset..)
+            .is_some_and(|head| head.starts_with(magic))
+    }) {
+        return Some(SkipReason::Magic);
+    }
+    None
+}
+
+// shannon entropy of the byte distribution
+pub fn entropy(data: &[u8]) -> f64 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let probability = *count as f64 / data.len() as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
+
+// high entropy alone is not enough, compressed data is only skipped
+// if a trial compression of the sample does not pay off either
+pub fn data_skip_reason(compression: &Compression, data: &[u8]) -> Result<Option<SkipReason>> {
+    let sample = &data[..data.len().min(SAMPLE_SIZE)];
+    if entropy(sample) < ENTROPY_THRESHOLD {
+        return Ok(None);
+    }
+    let trial_length = compression.compress(sample)?.len();
+    if trial_length as f64 > sample.len() as f64 * MIN_TRIAL_RATIO {
+        return Ok(Some(SkipReason::Entropy));
+    }
+    Ok(None)
+}
+
+// how often each path was taken for the chunks written by a backup
+#[derive(Debug, Default)]
+pub struct CompressionStatistics {
+    compressed: AtomicUsize,
+    skipped_by_extension: AtomicUsize,
+    skipped_by_magic: AtomicUsize,
+    skipped_by_entropy: AtomicUsize,
+    not_smaller: AtomicUsize,
+}
+
+impl CompressionStatistics {
+    fn counter(&self, skip_reason: Option<SkipReason>) -> &AtomicUsize {
+        match skip_reason {
+            None => &self.compressed,
+            Some(SkipReason::Extension) => &self.skipped_by_extension,
+            Some(SkipReason::Magic) => &self.skipped_by_magic,
+            Some(SkipReason::Entropy) => &self.skipped_by_entropy,
+            Some(SkipReason::NotSmaller) => &self.not_smaller,
+        }
+    }
+
+    pub fn record(&self, skip_reason: Option<SkipReason>) {
+        self.counter(skip_reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self, skip_reason: Option<SkipReason>) -> usize {
+        self.counter(skip_reason).load(Ordering::Relaxed)
+    }
+}
+
+impl Display for CompressionStatistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks compressed, stored raw: {} by extension, {} by magic bytes, {} by entropy, {} not smaller when compressed",
+            self.count(None),
+            self.count(Some(SkipReason::Extension)),
+            self.count(Some(SkipReason::Magic)),
+            self.count(Some(SkipReason::Entropy)),
+            self.count(Some(SkipReason::NotSmaller)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+
+    #[test]
+    fn compressibility_file_skip_reason() {
+        assert_eq!(
+            file_skip_reason(Path::new("holiday/IMG_0001.JPG"), b""),
+            Some(SkipReason::Extension)
+        );
+        assert_eq!(
+            file_skip_reason(Path::new("archive"), b"\x28\xb5\x2f\xfd\x00"),
+            Some(SkipReason::Magic)
+        );
+        assert_eq!(
+            file_skip_reason(Path::new("movie"), b"\x00\x00\x00\x20ftypisom"),
+            Some(SkipReason::Magic)
+        );
+        assert_eq!(file_skip_reason(Path::new("notes.txt"), b"PK"), None);
+    }
+
+    #[test]
+    fn compressibility_data_skip_reason() {
+        let compression = Compression::default();
+        let text = "compressible ".repeat(1000).into_bytes();
+        assert!(entropy(&text) < ENTROPY_THRESHOLD);
+        assert_eq!(data_skip_reason(&compression, &text).unwrap(), None);
+
+        let mut random = vec![0; SAMPLE_SIZE];
+        OsRng.fill_bytes(&mut random);
+        assert!(entropy(&random) > ENTROPY_THRESHOLD);
+        assert_eq!(
+            data_skip_reason(&compression, &random).unwrap(),
+            Some(SkipReason::Entropy)
+        );
+
+        // every byte value once, repeated, has maximal entropy but compresses well
+        let pattern: Vec<u8> = (0..=255).cycle().take(SAMPLE_SIZE).collect();
+        assert_eq!(data_skip_reason(&compression, &pattern).unwrap(), None);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+// how chunks are compressed before they are encrypted and stored
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", tag = "algorithm")]
+pub enum Compression {
+    None,
+    Zstd { level: i32 },
+    Lz4,
+    Brotli { level: u32 },
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Zstd {
+            level: Self::DEFAULT_ZSTD_LEVEL,
+        }
+    }
+}
+
+impl Compression {
+    // used by repositories and by zstd without a level
+    const DEFAULT_ZSTD_LEVEL: i32 = 1;
+    const DEFAULT_BROTLI_LEVEL: u32 = 6;
+    const BROTLI_BUFFER_SIZE: usize = 4096;
+    const BROTLI_WINDOW_SIZE: u32 = 22;
+
+    // stored in the chunk header, the level is not needed to decompress
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd { .. } => 1,
+            Compression::Lz4 => 2,
+            Compression::Brotli { .. } => 3,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Compression::Zstd { level } if !zstd::compression_level_range().contains(level) => {
+                Err(Error::msg(format!(
+                    "zstd level must be between {} and {}",
+                    zstd::compression_level_range().start(),
+                    zstd::compression_level_range().end()
+                )))
+            }
+            Compression::Brotli { level } if *level > 11 => {
+                Err(Error::msg("brotli level must be between 0 and 11"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Zstd { level } => zstd::encode_all(data, *level)?,
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+            Compression::Brotli { level } => {
+                let mut writer = brotli::CompressorWriter::new(
+                    Vec::new(),
+                    Self::BROTLI_BUFFER_SIZE,
+                    *level,
+                    Self::BROTLI_WINDOW_SIZE,
+                );
+                writer.write_all(data)?;
+                writer.into_inner()
+            }
+        })
+    }
+
+    pub fn decompress(id: u8, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match id {
+            0 => data.to_vec(),
+            1 => zstd::decode_all(data)?,
+            2 => lz4_flex::decompress_size_prepended(data)?,
+            3 => {
+                let mut decompressed_data = Vec::new();
+                brotli::Decompressor::new(data, Self::BROTLI_BUFFER_SIZE)
+                    .read_to_end(&mut decompressed_data)?;
+                decompressed_data
+            }
+            _ => return Err(Error::msg(format!("Unknown compression {}", id))),
+        })
+    }
+}
+
+// none, lz4, zstd, zstd:<level>, brotli or brotli:<level>
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (algorithm, level) = match value.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (value, None),
+        };
+        let invalid_level = |_| Error::msg(format!("Invalid compression level in {}", value));
+        let compression = match (algorithm, level) {
+            ("none", None) => Compression::None,
+            ("lz4", None) => Compression::Lz4,
+            ("zstd", level) => Compression::Zstd {
+                level: level
+                    .map_or(Ok(Self::DEFAULT_ZSTD_LEVEL), str::parse)
+                    .map_err(invalid_level)?,
+            },
+            ("brotli", level) => Compression::Brotli {
+                level: level
+                    .map_or(Ok(Self::DEFAULT_BROTLI_LEVEL), str::parse)
+                    .map_err(invalid_level)?,
+            },
+            _ => {
+                return Err(Error::msg(format!(
+                    "Unknown compression {}, use none, lz4, zstd[:level] or brotli[:level]",
+                    value
+                )))
+            }
+        };
+        compression.validate()?;
+        Ok(compression)
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Zstd { level } => write!(f, "zstd:{}", level),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Brotli { level } => write!(f, "brotli:{}", level),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_round_trip() {
+        let data = "compressible ".repeat(1000).into_bytes();
+        for compression in [
+            Compression::None,
+            Compression::Zstd { level: 19 },
+            Compression::Lz4,
+            Compression::Brotli { level: 5 },
+        ] {
+            let compressed_data = compression.compress(&data).unwrap();
+            if compression != Compression::None {
+                assert!(compressed_data.len() < data.len());
+            }
+            assert_eq!(
+                Compression::decompress(compression.id(), &compressed_data).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn compression_from_str() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!(
+            "zstd:19".parse::<Compression>().unwrap(),
+            Compression::Zstd { level: 19 }
+        );
+        assert_eq!(
+            "zstd".parse::<Compression>().unwrap(),
+            Compression::default()
+        );
+        assert_eq!(
+            "brotli".parse::<Compression>().unwrap(),
+            Compression::Brotli { level: 6 }
+        );
+        assert_eq!(
+            "zstd:7".parse::<Compression>().unwrap().to_string(),
+            "zstd:7"
+        );
+        assert!("zstd:100".parse::<Compression>().is_err());
+        assert!("lz4:1".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
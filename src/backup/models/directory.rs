@@ -0,0 +1,22 @@
+use crate::backup::models::file_attributes::FileAttributes;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Directory {
+    pub path: String,
+    pub attributes: Option<FileAttributes>,
+}
+
+impl Directory {
+    pub fn new(path: String) -> Directory {
+        Directory {
+            path,
+            attributes: None,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        Path::new(&self.path).components().count()
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::path::Path;
+
+// id -> ExtendedAttributes, files with the same attributes share one entry
+pub type ExtendedAttributesMap = HashMap<String, ExtendedAttributes>;
+
+// extended attributes of a file, including POSIX ACLs which linux stores as
+// system.posix_acl_access and system.posix_acl_default
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ExtendedAttributes {
+    // name -> value
+    pub attributes: BTreeMap<String, Vec<u8>>,
+}
+
+impl ExtendedAttributes {
+    // reads the attributes without following symlinks, None if there are none
+    pub fn read(path: &Path) -> Result<Option<ExtendedAttributes>> {
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            Err(err) if err.kind() == ErrorKind::Unsupported => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut attributes = BTreeMap::new();
+        for name in names {
+            let Some(name_string) = name.to_str() else {
+                warn!(
+                    "Skipping extended attribute {:?} of {}, the name is not valid UTF-8",
+                    name,
+                    path.display()
+                );
+                continue;
+            };
+            // removed between listing and reading
+            if let Some(value) = xattr::get(path, &name)? {
+                attributes.insert(name_string.to_string(), value);
+            }
+        }
+
+        if attributes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ExtendedAttributes { attributes }))
+    }
+
+    pub fn id(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        for (name, value) in self.attributes.iter() {
+            hasher.update(&(name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            hasher.update(&(value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    // attributes that can not be set, e.g. security labels without privileges,
+    // are logged and skipped
+    pub fn apply(&self, path: &Path) {
+        for (name, value) in self.attributes.iter() {
+            if let Err(err) = xattr::set(path, name, value) {
+                warn!(
+                    "Could not restore extended attribute {} of {}: {}",
+                    name,
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extended_attributes(attributes: &[(&str, &[u8])]) -> ExtendedAttributes {
+        ExtendedAttributes {
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_vec()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn extended_attributes_id_equal() {
+        assert_eq!(
+            extended_attributes(&[("user.a", b"1"), ("user.b", b"2")]).id(),
+            extended_attributes(&[("user.b", b"2"), ("user.a", b"1")]).id()
+        );
+    }
+
+    #[test]
+    fn extended_attributes_id_not_equal() {
+        assert_ne!(
+            extended_attributes(&[("user.a", b"12")]).id(),
+            extended_attributes(&[("user.a1", b"2")]).id()
+        );
+        assert_ne!(
+            extended_attributes(&[("user.a", b"1")]).id(),
+            extended_attributes(&[("user.a", b"2")]).id()
+        );
+    }
+}
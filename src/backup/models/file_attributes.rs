@@ -0,0 +1,62 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+
+// ownership, permissions and timestamps of a file system entry
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileAttributes {
+    // file type and permission bits as returned by stat
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub mtime: DateTime<Utc>,
+    pub atime: DateTime<Utc>,
+    // can not be restored, but tells whether the attributes changed
+    pub ctime: DateTime<Utc>,
+    // key of BackupMetadata.extended_attributes
+    #[serde(default)]
+    pub extended_attributes_id: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub inode: u64,
+}
+
+impl FileAttributes {
+    pub fn new(metadata: &Metadata, user: Option<String>, group: Option<String>) -> FileAttributes {
+        FileAttributes {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            user,
+            group,
+            mtime: Self::timestamp(metadata.mtime(), metadata.mtime_nsec()),
+            atime: Self::timestamp(metadata.atime(), metadata.atime_nsec()),
+            ctime: Self::timestamp(metadata.ctime(), metadata.ctime_nsec()),
+            extended_attributes_id: None,
+            size: metadata.len(),
+            inode: metadata.ino(),
+        }
+    }
+
+    // the content is assumed unchanged if none of these changed, writing a file
+    // updates mtime and ctime, replacing it changes the inode
+    pub fn is_unchanged(&self, previous: &FileAttributes) -> bool {
+        self.size == previous.size
+            && self.mtime == previous.mtime
+            && self.ctime == previous.ctime
+            && self.inode == previous.inode
+    }
+
+    fn timestamp(seconds: i64, nanoseconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(seconds, nanoseconds as u32).unwrap_or_default()
+    }
+
+    // permission bits including setuid, setgid and sticky
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+}
@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+// a range of a sparse file without allocated data, read as zeros
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileHole {
+    pub offset: u64,
+    pub length: u64,
+}
@@ -1,41 +1,78 @@
 use std::collections::HashMap;
 
+use crate::backup::models::file_attributes::FileAttributes;
 use crate::backup::models::file_chunk::FileChunk;
+use crate::backup::models::file_hole::FileHole;
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileMetadata {
     pub path: String,
-    // hash -> FileChunk
-    pub chunks: HashMap<String, FileChunk>,
+    // chunks ordered by offset, the same hash may appear multiple times
+    #[serde(deserialize_with = "deserialize_chunks")]
+    pub chunks: Vec<FileChunk>,
+    // missing in metadata written before attributes were captured
+    #[serde(default)]
+    pub attributes: Option<FileAttributes>,
+    // empty unless the file is sparse
+    #[serde(default)]
+    pub holes: Vec<FileHole>,
+}
+
+// metadata written before chunks were ordered stores them as hash -> FileChunk
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredFileChunks {
+    Ordered(Vec<FileChunk>),
+    Legacy(HashMap<String, FileChunk>),
+}
+
+fn deserialize_chunks<'de, D>(deserializer: D) -> Result<Vec<FileChunk>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match StoredFileChunks::deserialize(deserializer)? {
+        StoredFileChunks::Ordered(chunks) => chunks,
+        StoredFileChunks::Legacy(chunks) => chunks
+            .into_values()
+            .sorted_by(|a, b| Ord::cmp(&a.offset, &b.offset))
+            .collect(),
+    })
 }
 
 impl FileMetadata {
     pub fn new(path: String) -> FileMetadata {
         FileMetadata {
             path,
-            chunks: HashMap::new(),
+            chunks: Vec::new(),
+            attributes: None,
+            holes: Vec::new(),
         }
     }
 
+    // holes are not covered by chunks, a trailing hole only by its own range
+    pub fn size(&self) -> u64 {
+        let chunks_end = self
+            .chunks
+            .last()
+            .map(|file_chunk| file_chunk.offset + file_chunk.length as u64);
+        let holes_end = self.holes.last().map(|hole| hole.offset + hole.length);
+        chunks_end.max(holes_end).unwrap_or(0)
+    }
+
     pub fn fingerprint(&self) -> String {
         let mut hasher = blake3::Hasher::new();
 
-        self.chunks
-            .iter()
-            .sorted_by(|(_, file_chunk_a), (_, file_chunk_b)| {
-                Ord::cmp(&file_chunk_a.offset, &file_chunk_b.offset)
-            })
-            .for_each(|(hash, _)| {
-                hasher.update(hash.to_string().as_bytes());
-            });
+        self.chunks.iter().for_each(|file_chunk| {
+            hasher.update(file_chunk.hash.as_bytes());
+        });
 
         hasher.finalize().to_hex().to_string()
     }
 
-    pub fn add_chunk(&mut self, hash: String, file_chunk: FileChunk) {
-        self.chunks.insert(hash, file_chunk);
+    pub fn add_chunk(&mut self, file_chunk: FileChunk) {
+        self.chunks.push(file_chunk);
     }
 
     pub fn key(&self) -> String {
@@ -47,43 +84,24 @@ impl FileMetadata {
 mod tests {
     use super::*;
 
-    #[test]
-    fn file_metadata_fingerprint_equal() {
-        let hashes = Vec::from(["123", "456", "789"]);
-        let mut chunks: HashMap<String, FileChunk> = HashMap::new();
-        let mut second_chunks: HashMap<String, FileChunk> = HashMap::new();
-
+    fn file_metadata_from_hashes(hashes: &[&str]) -> FileMetadata {
+        let mut file_metadata = FileMetadata::new("".to_string());
         for (index, hash) in hashes.iter().enumerate() {
-            chunks.insert(
-                hash.to_string(),
-                FileChunk {
-                    hash: hash.to_string(),
-                    offset: index.clone() as u64,
-                    length: 8,
-                },
-            );
-        }
-
-        for (index, hash) in hashes.iter().enumerate().rev() {
-            second_chunks.insert(
-                hash.to_string(),
-                FileChunk {
-                    hash: hash.to_string(),
-                    offset: index.clone() as u64,
-                    length: 8,
-                },
-            );
+            file_metadata.add_chunk(FileChunk {
+                hash: hash.to_string(),
+                offset: index as u64 * 8,
+                length: 8,
+            });
         }
+        file_metadata
+    }
 
-        let file_metadata = FileMetadata {
-            path: "".to_string(),
-            chunks: chunks,
-        };
+    #[test]
+    fn file_metadata_fingerprint_equal() {
+        let hashes = Vec::from(["123", "456", "789"]);
 
-        let second_file_metadata = FileMetadata {
-            path: "".to_string(),
-            chunks: second_chunks,
-        };
+        let file_metadata = file_metadata_from_hashes(&hashes);
+        let second_file_metadata = file_metadata_from_hashes(&hashes);
         assert_eq!(
             file_metadata.fingerprint(),
             second_file_metadata.fingerprint()
@@ -94,43 +112,42 @@ mod tests {
     fn file_metadata_fingerprint_not_equal() {
         let hashes = Vec::from(["123", "456", "789"]);
         let other_hashes = Vec::from(["234", "567", "890"]);
-        let mut chunks: HashMap<String, FileChunk> = HashMap::new();
-        let mut second_chunks: HashMap<String, FileChunk> = HashMap::new();
-
-        for (index, hash) in hashes.iter().enumerate() {
-            chunks.insert(
-                hash.to_string(),
-                FileChunk {
-                    hash: hash.to_string(),
-                    offset: index.clone() as u64,
-                    length: 8,
-                },
-            );
-        }
 
-        for (index, hash) in other_hashes.iter().enumerate() {
-            second_chunks.insert(
-                hash.to_string(),
-                FileChunk {
-                    hash: hash.to_string(),
-                    offset: index.clone() as u64,
-                    length: 8,
-                },
-            );
-        }
+        let file_metadata = file_metadata_from_hashes(&hashes);
+        let second_file_metadata = file_metadata_from_hashes(&other_hashes);
+        assert_ne!(
+            file_metadata.fingerprint(),
+            second_file_metadata.fingerprint()
+        )
+    }
 
-        let file_metadata = FileMetadata {
-            path: "".to_string(),
-            chunks: chunks,
-        };
+    #[test]
+    fn file_metadata_fingerprint_repeated_chunks() {
+        let file_metadata = file_metadata_from_hashes(&["123", "456", "123"]);
+        let second_file_metadata = file_metadata_from_hashes(&["123", "456"]);
 
-        let second_file_metadata = FileMetadata {
-            path: "".to_string(),
-            chunks: second_chunks,
-        };
+        assert_eq!(file_metadata.chunks.len(), 3);
         assert_ne!(
             file_metadata.fingerprint(),
             second_file_metadata.fingerprint()
         )
     }
+
+    #[test]
+    fn file_metadata_deserialize_legacy_chunks() {
+        let legacy_json = r#"{"path":"file","chunks":{
+            "456":{"hash":"456","offset":8,"length":8},
+            "123":{"hash":"123","offset":0,"length":8}
+        }}"#;
+
+        let file_metadata: FileMetadata = serde_json::from_str(legacy_json).unwrap();
+        assert_eq!(
+            file_metadata
+                .chunks
+                .iter()
+                .map(|file_chunk| file_chunk.offset)
+                .collect::<Vec<u64>>(),
+            vec![0, 8]
+        );
+    }
 }
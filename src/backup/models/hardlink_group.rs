@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+// paths sharing one inode, only the first path is chunked and restored
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HardlinkGroup {
+    // key of the FileMetadata holding the content
+    pub path: String,
+    // further paths linked to path
+    pub links: Vec<String>,
+}
+
+impl HardlinkGroup {
+    pub fn new(path: String) -> HardlinkGroup {
+        HardlinkGroup {
+            path,
+            links: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::backup::models::repository_key::RepositoryKey;
+use anyhow::{Error, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// the repository key, encrypted with a key derived from the password
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyFile {
+    pub kdf: String,
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    // hex encoded
+    pub salt: String,
+    // hex encoded nonce || encrypted repository key
+    pub encrypted_key: String,
+}
+
+impl KeyFile {
+    const KEY_FILE: &'static str = "key";
+    const KDF_ARGON2ID: &'static str = "argon2id";
+
+    pub fn path(directory_path: &Path) -> PathBuf {
+        directory_path.join(Self::KEY_FILE)
+    }
+
+    pub fn exists(directory_path: &Path) -> bool {
+        Self::path(directory_path).exists()
+    }
+
+    fn derive_password_key(
+        password: &str,
+        salt: &[u8],
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    ) -> Result<RepositoryKey> {
+        let params = Params::new(memory_cost, time_cost, parallelism, Some(32))
+            .map_err(|err| Error::msg(format!("Invalid key derivation parameters: {}", err)))?;
+        let mut password_key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), salt, &mut password_key)
+            .map_err(|err| Error::msg(format!("Could not derive key: {}", err)))?;
+        Ok(RepositoryKey::from_bytes(password_key))
+    }
+
+    // writes a new key file protecting repository_key with password
+    pub fn create(
+        directory_path: &Path,
+        password: &str,
+        repository_key: &RepositoryKey,
+    ) -> Result<KeyFile> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        let password_key = Self::derive_password_key(
+            password,
+            &salt,
+            Params::DEFAULT_M_COST,
+            Params::DEFAULT_T_COST,
+            Params::DEFAULT_P_COST,
+        )?;
+        let key_file = KeyFile {
+            kdf: Self::KDF_ARGON2ID.to_string(),
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            salt: hex::encode(salt),
+            encrypted_key: hex::encode(
+                password_key.encrypt(repository_key.as_bytes(), Self::KEY_FILE.as_bytes())?,
+            ),
+        };
+
+        fs::create_dir_all(directory_path)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Self::path(directory_path))?;
+        file.write_all(&serde_json::to_vec_pretty(&key_file)?)?;
+        file.sync_all()?;
+        Ok(key_file)
+    }
+
+    pub fn open(directory_path: &Path, password: &str) -> Result<RepositoryKey> {
+        let key_file: KeyFile = serde_json::from_slice(&fs::read(Self::path(directory_path))?)?;
+        if key_file.kdf != Self::KDF_ARGON2ID {
+            return Err(Error::msg(format!(
+                "Unknown key derivation function: {}",
+                key_file.kdf
+            )));
+        }
+
+        let password_key = Self::derive_password_key(
+            password,
+            &hex::decode(&key_file.salt)?,
+            key_file.memory_cost,
+            key_file.time_cost,
+            key_file.parallelism,
+        )?;
+        let master_key: [u8; 32] = password_key
+            .decrypt(
+                &hex::decode(&key_file.encrypted_key)?,
+                Self::KEY_FILE.as_bytes(),
+            )
+            .map_err(|_| Error::msg("Wrong password"))?
+            .try_into()
+            .map_err(|_| Error::msg("Key file contains an invalid key"))?;
+        Ok(RepositoryKey::from_bytes(master_key))
+    }
+}
@@ -1,4 +1,18 @@
-use std::path::{Path, PathBuf};
+use crate::backup::models::backup_metadata::SerializationType;
+use crate::backup::models::repository_key::RepositoryKey;
+use anyhow::{Error, Result};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
 
 pub fn split_hash(hash: &str) -> &str {
     &hash[0..2]
@@ -8,3 +22,281 @@ pub fn split_hash_as_path(prefix_path: &Path, hash: String) -> PathBuf {
         .join(split_hash(&hash))
         .join(hash)
 }
+
+// resolves . and .. without touching the file system and drops the root,
+// fails if the path leaves the directory it is relative to
+pub fn normalize_relative_path(path: &Path) -> Result<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative_path.push(name),
+            Component::ParentDir => {
+                if !relative_path.pop() {
+                    return Err(Error::msg(format!(
+                        "{} leads outside of its directory",
+                        path.display()
+                    )));
+                }
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    Ok(relative_path)
+}
+
+// associated_data binds encrypted bytes to the role and name of their file,
+// so one encrypted file cannot be swapped for another
+pub fn serialize_to_bytes<T: Serialize>(
+    value: &T,
+    serialization_type: SerializationType,
+    repository_key: Option<&RepositoryKey>,
+    associated_data: &[u8],
+) -> Result<Vec<u8>> {
+    let bytes = match serialization_type {
+        SerializationType::JSON => serde_json::to_vec(value)?,
+        SerializationType::MessagePack => rmp_serde::to_vec_named(value)?,
+    };
+
+    match repository_key {
+        Some(repository_key) => repository_key.encrypt(&bytes, associated_data),
+        None => Ok(bytes),
+    }
+}
+
+pub fn deserialize_from_bytes<T: DeserializeOwned>(
+    bytes: &[u8],
+    repository_key: Option<&RepositoryKey>,
+    associated_data: &[u8],
+) -> Result<T> {
+    let decrypted_bytes;
+    let bytes = match repository_key {
+        Some(repository_key) => {
+            decrypted_bytes = repository_key.decrypt(bytes, associated_data)?;
+            decrypted_bytes.as_slice()
+        }
+        None => bytes,
+    };
+
+    debug!("Trying deserialize as json");
+    if let Ok(value) = serde_json::from_slice::<T>(bytes) {
+        return Ok(value);
+    }
+
+    debug!("Trying deserialize as messagepack");
+    if let Ok(value) = rmp_serde::from_slice::<T>(bytes) {
+        return Ok(value);
+    }
+
+    Err(Error::msg("Could not deserialize"))
+}
+
+// writes to a temporary file first, so readers never see a half written file,
+// its name is unique, so writers on other hosts never truncate each other's file
+pub fn serialize_to_file<T: Serialize>(
+    path: &Path,
+    value: &T,
+    serialization_type: SerializationType,
+    repository_key: Option<&RepositoryKey>,
+    associated_data: &[u8],
+) -> Result<()> {
+    let bytes = serialize_to_bytes(value, serialization_type, repository_key, associated_data)?;
+    let mut suffix = [0u8; 8];
+    OsRng.fill_bytes(&mut suffix);
+    let temporary_path = path.with_extension(format!("{}.tmp", hex::encode(suffix)));
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(bytes.as_slice())?;
+    file.sync_all()?;
+    Ok(fs::rename(temporary_path, path)?)
+}
+
+// fails if the file already exists, used for immutable files like snapshots
+pub fn serialize_to_new_file<T: Serialize>(
+    path: &Path,
+    value: &T,
+    serialization_type: SerializationType,
+    repository_key: Option<&RepositoryKey>,
+    associated_data: &[u8],
+) -> Result<()> {
+    let bytes = serialize_to_bytes(value, serialization_type, repository_key, associated_data)?;
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(bytes.as_slice())?;
+    Ok(file.sync_all()?)
+}
+
+pub fn deserialize_from_file<T: DeserializeOwned>(
+    path: &Path,
+    repository_key: Option<&RepositoryKey>,
+    associated_data: &[u8],
+) -> Result<T> {
+    deserialize_from_bytes(&fs::read(path)?, repository_key, associated_data)
+        .map_err(|err| Error::msg(format!("{}: {}", path.display(), err)))
+}
+
+fn passwd_buffer_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        size if size > 0 => size as usize,
+        _ => 16384,
+    }
+}
+
+fn group_buffer_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_GETGR_R_SIZE_MAX) } {
+        size if size > 0 => size as usize,
+        _ => 16384,
+    }
+}
+
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; passwd_buffer_size()];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy()
+            .to_string(),
+    )
+}
+
+pub fn group_name(gid: u32) -> Option<String> {
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; group_buffer_size()];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getgrgid_r(
+            gid,
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(group.gr_name) }
+            .to_string_lossy()
+            .to_string(),
+    )
+}
+
+pub fn user_id(user_name: &str) -> Option<u32> {
+    let user_name = CString::new(user_name).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; passwd_buffer_size()];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            user_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(passwd.pw_uid)
+}
+
+pub fn group_id(group_name: &str) -> Option<u32> {
+    let group_name = CString::new(group_name).ok()?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; group_buffer_size()];
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getgrnam_r(
+            group_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+    Some(group.gr_gid)
+}
+
+// sets access and modification time without following symlinks
+pub fn set_file_times(path: &Path, accessed: DateTime<Utc>, modified: DateTime<Utc>) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: accessed.timestamp() as libc::time_t,
+            tv_nsec: accessed.timestamp_subsec_nanos() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: modified.timestamp() as libc::time_t,
+            tv_nsec: modified.timestamp_subsec_nanos() as libc::c_long,
+        },
+    ];
+    let status = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// ranges of the file containing data, None if the file system can not tell
+pub fn data_ranges(file: &File, length: u64) -> Result<Option<Vec<Range<u64>>>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < length {
+        let data_start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data_start < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                // no data after offset, the rest is a hole
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) => return Ok(None),
+                _ => return Err(err.into()),
+            }
+        }
+        let data_end = unsafe { libc::lseek(fd, data_start, libc::SEEK_HOLE) };
+        if data_end < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        ranges.push(data_start as u64..data_end as u64);
+        offset = data_end as u64;
+    }
+    Ok(Some(ranges))
+}
+
+// creates a fifo or device node, mode includes the file type bits
+pub fn make_node(path: &Path, mode: u32, major: u32, minor: u32) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let status = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            mode as libc::mode_t,
+            libc::makedev(major, minor),
+        )
+    };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
@@ -1,7 +1,28 @@
 pub mod backup_config;
 pub mod backup_metadata;
+pub mod backup_options;
 pub mod chunk;
+pub mod chunk_header;
+pub mod chunk_index;
+pub mod chunker_parameters;
+pub mod compressibility;
+pub mod compression;
+pub mod directory;
+pub mod extended_attributes;
+pub mod file_attributes;
 pub mod file_chunk;
+pub mod file_hole;
 pub mod file_metadata;
+pub mod hardlink_group;
+pub mod key_file;
 pub mod lib;
+pub mod pack_index;
+pub mod repository_config;
+pub mod repository_key;
+pub mod restore_options;
+pub mod retention_policy;
+pub mod snapshot;
+pub mod special_file;
+pub mod storage_url;
 pub mod symlink;
+pub mod zstd_dictionary;
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// location of a stored chunk inside a pack file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PackEntry {
+    pub hash: String,
+    pub pack: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// the index of one pack, stored next to it, so backups running in parallel
+// never overwrite each other's entries
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct PackFileIndex {
+    pub size: u64,
+    pub entries: Vec<PackEntry>,
+}
+
+// all pack file indexes of a repository, merged
+#[derive(Debug, Default)]
+pub struct PackIndex {
+    entries: HashMap<String, PackEntry>,
+    pack_sizes: HashMap<String, u64>,
+}
+
+impl PackIndex {
+    pub fn add_pack(&mut self, pack: &str, pack_file_index: PackFileIndex) {
+        self.pack_sizes
+            .insert(pack.to_string(), pack_file_index.size);
+        for pack_entry in pack_file_index.entries {
+            self.entries.insert(pack_entry.hash.clone(), pack_entry);
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&PackEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn remove(&mut self, hash: &str) -> Option<PackEntry> {
+        self.entries.remove(hash)
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn packs(&self) -> Vec<String> {
+        self.pack_sizes.keys().cloned().collect()
+    }
+
+    // entries still referencing the pack, ordered by offset
+    pub fn pack_file_index(&self, pack: &str) -> PackFileIndex {
+        let mut entries: Vec<PackEntry> = self
+            .entries
+            .values()
+            .filter(|pack_entry| pack_entry.pack == pack)
+            .cloned()
+            .collect();
+        entries.sort_by_key(|pack_entry| pack_entry.offset);
+        PackFileIndex {
+            size: self.pack_sizes.get(pack).copied().unwrap_or_default(),
+            entries,
+        }
+    }
+
+    pub fn remove_pack(&mut self, pack: &str) {
+        self.pack_sizes.remove(pack);
+        self.entries.retain(|_, pack_entry| pack_entry.pack != pack);
+    }
+}
+
+impl PackFileIndex {
+    // share of the pack taken by chunks that were removed from it
+    pub fn unused_ratio(&self) -> f64 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        let used_size: u64 = self
+            .entries
+            .iter()
+            .map(|pack_entry| pack_entry.length)
+            .sum();
+        1.0 - used_size as f64 / self.size as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack_entry(hash: &str, pack: &str, offset: u64) -> PackEntry {
+        PackEntry {
+            hash: hash.to_string(),
+            pack: pack.to_string(),
+            offset,
+            length: 10,
+        }
+    }
+
+    #[test]
+    fn pack_index_unused_ratio() {
+        let mut pack_index = PackIndex::default();
+        pack_index.add_pack(
+            "pack",
+            PackFileIndex {
+                size: 40,
+                entries: vec![
+                    pack_entry("b", "pack", 10),
+                    pack_entry("a", "pack", 0),
+                    pack_entry("c", "pack", 20),
+                    pack_entry("d", "pack", 30),
+                ],
+            },
+        );
+        assert_eq!(pack_index.pack_file_index("pack").unused_ratio(), 0.0);
+
+        pack_index.remove("a");
+        pack_index.remove("c");
+        let pack_file_index = pack_index.pack_file_index("pack");
+        assert_eq!(
+            pack_file_index
+                .entries
+                .iter()
+                .map(|pack_entry| pack_entry.offset)
+                .collect::<Vec<u64>>(),
+            vec![10, 30]
+        );
+        assert_eq!(pack_file_index.unused_ratio(), 0.5);
+
+        pack_index.remove_pack("pack");
+        assert!(pack_index.get("b").is_none());
+        assert!(pack_index.packs().is_empty());
+    }
+}
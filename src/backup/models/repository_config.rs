@@ -0,0 +1,163 @@
+use crate::backup::models::backup_metadata::SerializationType;
+use crate::backup::models::chunker_parameters::ChunkerParameters;
+use crate::backup::models::compression::Compression;
+use crate::backup::models::lib::serialize_to_file;
+use crate::backup::models::storage_url::StorageUrl;
+use anyhow::{Error, Result};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChunkIdScheme {
+    // blake3 hash of the chunk content
+    #[default]
+    Blake3,
+    // blake3 keyed with a secret derived from the repository key
+    KeyedBlake3,
+}
+
+// settings every command has to use to read and write the repository,
+// stored unencrypted so it can be read before the key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RepositoryConfig {
+    pub chunk_id_scheme: ChunkIdScheme,
+    // stored by init or the first backup, so later backups chunk the same way
+    #[serde(default)]
+    pub chunker_parameters: Option<ChunkerParameters>,
+    // configs written before the format was versioned are version 1
+    #[serde(default = "RepositoryConfig::first_version")]
+    pub version: u32,
+    // empty for repositories created before init existed
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub compression: Compression,
+    // chunks are stored in the repository directory if not set
+    #[serde(default)]
+    pub storage_url: Option<StorageUrl>,
+    // the zstd dictionary new chunks are compressed with, set by train-dictionary
+    #[serde(default)]
+    pub dictionary_id: Option<u32>,
+}
+
+impl Default for RepositoryConfig {
+    fn default() -> Self {
+        RepositoryConfig {
+            chunk_id_scheme: Default::default(),
+            chunker_parameters: None,
+            version: Self::VERSION,
+            id: Default::default(),
+            compression: Default::default(),
+            storage_url: None,
+            dictionary_id: None,
+        }
+    }
+}
+
+impl RepositoryConfig {
+    const REPOSITORY_CONFIG_FILE: &'static str = "config";
+    // the repository format written by this version, older ones are still read:
+    // 1 loose chunks
+    // 2 packs, chunk headers with compression and dictionary, storage urls
+    pub const VERSION: u32 = 2;
+
+    fn first_version() -> u32 {
+        1
+    }
+
+    pub fn new(chunk_id_scheme: ChunkIdScheme, chunker_parameters: ChunkerParameters) -> Self {
+        let mut id = [0u8; 32];
+        OsRng.fill_bytes(&mut id);
+        RepositoryConfig {
+            chunk_id_scheme,
+            chunker_parameters: Some(chunker_parameters),
+            id: hex::encode(id),
+            ..Default::default()
+        }
+    }
+
+    pub fn path(directory_path: &Path) -> PathBuf {
+        directory_path.join(Self::REPOSITORY_CONFIG_FILE)
+    }
+
+    pub fn serialize(&self, directory_path: &Path) -> Result<()> {
+        fs::create_dir_all(directory_path)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(Self::path(directory_path))?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(file.sync_all()?)
+    }
+
+    // replaces an existing config
+    pub fn update(&self, directory_path: &Path) -> Result<()> {
+        fs::create_dir_all(directory_path)?;
+        // never encrypted, it is read before the key
+        serialize_to_file(
+            &Self::path(directory_path),
+            self,
+            SerializationType::JSON,
+            None,
+            &[],
+        )
+    }
+
+    // fails for directories without a config and for formats this version can not read
+    pub fn open(directory_path: &Path) -> Result<RepositoryConfig> {
+        if !Self::path(directory_path).exists() {
+            return Err(Error::msg(format!(
+                "{} is not a repository, create it with init",
+                directory_path.display()
+            )));
+        }
+        let repository_config = Self::deserialize(directory_path)?;
+        if repository_config.version > Self::VERSION {
+            return Err(Error::msg(format!(
+                "Repository {} uses format version {}, but only versions up to {} are supported, please update",
+                directory_path.display(),
+                repository_config.version,
+                Self::VERSION
+            )));
+        }
+        if repository_config.version < Self::first_version() {
+            return Err(Error::msg(format!(
+                "Repository {} uses unknown format version {}",
+                directory_path.display(),
+                repository_config.version
+            )));
+        }
+        Ok(repository_config)
+    }
+
+    // called before data in the current format is written, so older versions
+    // refuse to open the repository afterwards
+    pub fn upgrade(directory_path: &Path) -> Result<()> {
+        let mut repository_config = Self::deserialize(directory_path)?;
+        if repository_config.version < Self::VERSION {
+            info!(
+                "Upgrading repository {} from format version {} to {}",
+                directory_path.display(),
+                repository_config.version,
+                Self::VERSION
+            );
+            repository_config.version = Self::VERSION;
+            repository_config.update(directory_path)?;
+        }
+        Ok(())
+    }
+
+    // repositories without a config file use the defaults
+    pub fn deserialize(directory_path: &Path) -> Result<RepositoryConfig> {
+        let path = Self::path(directory_path);
+        if !path.exists() {
+            return Ok(RepositoryConfig::default());
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{Error, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+// the secret all repository keys are derived from, never stored unencrypted
+#[derive(Clone)]
+pub struct RepositoryKey {
+    master_key: [u8; 32],
+}
+
+impl fmt::Debug for RepositoryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RepositoryKey(..)")
+    }
+}
+
+impl RepositoryKey {
+    const ENCRYPTION_KEY_CONTEXT: &'static str = "hoard_chunker 2024 encryption key";
+    const CHUNK_ID_KEY_CONTEXT: &'static str = "hoard_chunker 2024 chunk id key";
+    const NONCE_LENGTH: usize = 24;
+
+    pub fn generate() -> RepositoryKey {
+        RepositoryKey {
+            master_key: XChaCha20Poly1305::generate_key(&mut OsRng).into(),
+        }
+    }
+
+    pub fn from_bytes(master_key: [u8; 32]) -> RepositoryKey {
+        RepositoryKey { master_key }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.master_key
+    }
+
+    pub fn chunk_id_key(&self) -> [u8; 32] {
+        blake3::derive_key(Self::CHUNK_ID_KEY_CONTEXT, &self.master_key)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        let encryption_key = blake3::derive_key(Self::ENCRYPTION_KEY_CONTEXT, &self.master_key);
+        XChaCha20Poly1305::new(&encryption_key.into())
+    }
+
+    // returns nonce || ciphertext, associated_data is authenticated but not stored
+    pub fn encrypt(&self, data: &[u8], associated_data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: data,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::msg("Could not encrypt data"))?;
+
+        let mut encrypted_data = nonce.to_vec();
+        encrypted_data.extend(ciphertext);
+        Ok(encrypted_data)
+    }
+
+    pub fn decrypt(&self, encrypted_data: &[u8], associated_data: &[u8]) -> Result<Vec<u8>> {
+        if encrypted_data.len() < Self::NONCE_LENGTH {
+            return Err(Error::msg("Encrypted data is truncated"));
+        }
+        let (nonce, ciphertext) = encrypted_data.split_at(Self::NONCE_LENGTH);
+        self.cipher()
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::msg("Could not decrypt data, it was modified or the key is wrong"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repository_key_encrypt_decrypt() {
+        let repository_key = RepositoryKey::generate();
+        let encrypted_data = repository_key.encrypt(b"data", b"hash").unwrap();
+
+        assert_ne!(&encrypted_data[RepositoryKey::NONCE_LENGTH..], b"data");
+        assert_eq!(
+            repository_key.decrypt(&encrypted_data, b"hash").unwrap(),
+            b"data"
+        );
+    }
+
+    #[test]
+    fn repository_key_decrypt_tampered() {
+        let repository_key = RepositoryKey::generate();
+        let mut encrypted_data = repository_key.encrypt(b"data", b"hash").unwrap();
+
+        assert!(repository_key
+            .decrypt(&encrypted_data, b"other hash")
+            .is_err());
+        assert!(RepositoryKey::generate()
+            .decrypt(&encrypted_data, b"hash")
+            .is_err());
+
+        let last = encrypted_data.len() - 1;
+        encrypted_data[last] ^= 1;
+        assert!(repository_key.decrypt(&encrypted_data, b"hash").is_err());
+    }
+}
@@ -0,0 +1,19 @@
+#[derive(Debug, Clone)]
+pub struct RestoreOptions {
+    // changing the owner to other users requires root
+    pub restore_ownership: bool,
+    // absolute symlink targets point into the restore path instead of the original location
+    pub rewrite_absolute_symlinks: bool,
+    // extended attributes and ACLs
+    pub restore_extended_attributes: bool,
+}
+
+impl Default for RestoreOptions {
+    fn default() -> Self {
+        RestoreOptions {
+            restore_ownership: true,
+            rewrite_absolute_symlinks: false,
+            restore_extended_attributes: true,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use crate::backup::models::snapshot::Snapshot;
+use chrono::{DateTime, Local};
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::time::Duration;
+
+#[derive(Debug, Default, Clone)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<usize>,
+    pub keep_hourly: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+    // relative to the latest snapshot, not to the current time
+    pub keep_within: Option<Duration>,
+}
+
+struct RetentionBucket {
+    remaining: usize,
+    format: &'static str,
+    last_key: Option<String>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_hourly.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+            && self.keep_yearly.is_none()
+            && self.keep_within.is_none()
+    }
+
+    // ids of the snapshots to keep, the newest snapshot of every hour/day/week/... bucket
+    // is kept until the number of buckets of that policy is used up
+    pub fn keep(&self, snapshots: &[Snapshot]) -> HashSet<String> {
+        let mut kept_snapshot_ids = HashSet::new();
+        let snapshots: Vec<&Snapshot> = snapshots
+            .iter()
+            .sorted_by(|a, b| Ord::cmp(&b.time, &a.time))
+            .collect();
+        let Some(latest_time) = snapshots.first().map(|snapshot| snapshot.time) else {
+            return kept_snapshot_ids;
+        };
+
+        let mut buckets: Vec<RetentionBucket> = [
+            (self.keep_hourly, "%Y%m%d%H"),
+            (self.keep_daily, "%Y%m%d"),
+            (self.keep_weekly, "%G%V"),
+            (self.keep_monthly, "%Y%m"),
+            (self.keep_yearly, "%Y"),
+        ]
+        .into_iter()
+        .filter_map(|(keep, format)| {
+            keep.map(|remaining| RetentionBucket {
+                remaining,
+                format,
+                last_key: None,
+            })
+        })
+        .collect();
+
+        for (index, snapshot) in snapshots.iter().enumerate() {
+            let mut keep = self.keep_last.is_some_and(|keep_last| index < keep_last)
+                || self.is_within(latest_time, snapshot.time);
+
+            for bucket in buckets.iter_mut() {
+                if bucket.remaining == 0 {
+                    continue;
+                }
+                let key = snapshot.time.format(bucket.format).to_string();
+                if bucket.last_key.as_ref() != Some(&key) {
+                    bucket.last_key = Some(key);
+                    bucket.remaining -= 1;
+                    keep = true;
+                }
+            }
+
+            if keep {
+                kept_snapshot_ids.insert(snapshot.id.clone());
+            }
+        }
+        kept_snapshot_ids
+    }
+
+    fn is_within(&self, latest_time: DateTime<Local>, time: DateTime<Local>) -> bool {
+        match self.keep_within {
+            Some(keep_within) => (latest_time - time)
+                .to_std()
+                .is_ok_and(|age| age <= keep_within),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn snapshot(id: &str, year: i32, month: u32, day: u32, hour: u32) -> Snapshot {
+        Snapshot {
+            id: id.to_string(),
+            time: Local
+                .with_ymd_and_hms(year, month, day, hour, 0, 0)
+                .unwrap(),
+            hostname: "host".to_string(),
+            source_path: "/data".to_string(),
+        }
+    }
+
+    fn kept(retention_policy: &RetentionPolicy, snapshots: &[Snapshot]) -> Vec<String> {
+        retention_policy
+            .keep(snapshots)
+            .into_iter()
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn retention_policy_keep_last() {
+        let snapshots = vec![
+            snapshot("a", 2024, 1, 1, 10),
+            snapshot("b", 2024, 1, 2, 10),
+            snapshot("c", 2024, 1, 3, 10),
+        ];
+        let retention_policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(kept(&retention_policy, &snapshots), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn retention_policy_keep_daily_keeps_newest_of_each_day() {
+        let snapshots = vec![
+            snapshot("a", 2024, 1, 1, 10),
+            snapshot("b", 2024, 1, 1, 20),
+            snapshot("c", 2024, 1, 2, 10),
+            snapshot("d", 2024, 1, 2, 20),
+            snapshot("e", 2024, 1, 3, 10),
+        ];
+        let retention_policy = RetentionPolicy {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(kept(&retention_policy, &snapshots), vec!["d", "e"]);
+    }
+
+    #[test]
+    fn retention_policy_combined() {
+        let snapshots = vec![
+            snapshot("a", 2023, 6, 1, 10),
+            snapshot("b", 2024, 1, 15, 10),
+            snapshot("c", 2024, 2, 15, 10),
+            snapshot("d", 2024, 3, 1, 10),
+            snapshot("e", 2024, 3, 2, 10),
+        ];
+        let retention_policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_monthly: Some(2),
+            keep_yearly: Some(2),
+            keep_within: Some(Duration::from_secs(60 * 60 * 24)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(&retention_policy, &snapshots),
+            vec!["a", "c", "d", "e"]
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub id: String,
+    pub time: DateTime<Local>,
+    pub hostname: String,
+    pub source_path: String,
+}
+
+impl Snapshot {
+    pub fn new(source_path: String) -> Snapshot {
+        let time = Local::now();
+        let hostname = hostname::get()
+            .map(|hostname| hostname.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(time.to_rfc3339_opts(SecondsFormat::Nanos, true).as_bytes());
+        hasher.update(hostname.as_bytes());
+        hasher.update(source_path.as_bytes());
+
+        Snapshot {
+            id: hasher.finalize().to_hex().to_string(),
+            time,
+            hostname,
+            source_path,
+        }
+    }
+
+    pub fn short_id(&self) -> &str {
+        &self.id[0..self.id.len().min(8)]
+    }
+}
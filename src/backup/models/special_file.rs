@@ -0,0 +1,59 @@
+use crate::backup::models::file_attributes::FileAttributes;
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpecialFileType {
+    Fifo,
+    CharacterDevice,
+    BlockDevice,
+}
+
+impl SpecialFileType {
+    // sockets are not included, they only exist while a process listens on them
+    pub fn from_metadata(metadata: &Metadata) -> Option<SpecialFileType> {
+        let file_type = metadata.file_type();
+        if file_type.is_fifo() {
+            Some(SpecialFileType::Fifo)
+        } else if file_type.is_char_device() {
+            Some(SpecialFileType::CharacterDevice)
+        } else if file_type.is_block_device() {
+            Some(SpecialFileType::BlockDevice)
+        } else {
+            None
+        }
+    }
+
+    // file type bits of st_mode
+    pub fn mode(&self) -> u32 {
+        match self {
+            SpecialFileType::Fifo => libc::S_IFIFO,
+            SpecialFileType::CharacterDevice => libc::S_IFCHR,
+            SpecialFileType::BlockDevice => libc::S_IFBLK,
+        }
+    }
+}
+
+// fifos and device nodes have no content, only their type and device number are stored
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SpecialFile {
+    pub path: String,
+    pub file_type: SpecialFileType,
+    pub major: u32,
+    pub minor: u32,
+    pub attributes: Option<FileAttributes>,
+}
+
+impl SpecialFile {
+    pub fn new(path: String, file_type: SpecialFileType, metadata: &Metadata) -> SpecialFile {
+        SpecialFile {
+            path,
+            file_type,
+            major: libc::major(metadata.rdev()),
+            minor: libc::minor(metadata.rdev()),
+            attributes: None,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use anyhow::{Error, Result};
+use opendal::Scheme;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::path::{self, Path};
+use std::str::FromStr;
+use url::Url;
+
+// where chunks are stored: fs:///path, s3://bucket/prefix, sftp://user@host:port/path or memory://
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct StorageUrl {
+    url: Url,
+}
+
+impl StorageUrl {
+    pub const SFTP_KEY_VARIABLE: &'static str = "HOARD_SFTP_KEY";
+
+    pub fn parse(value: &str) -> Result<StorageUrl> {
+        // plain paths are local directories
+        if !value.contains("://") {
+            return Self::from_path(Path::new(value));
+        }
+
+        let url = Url::parse(value)
+            .map_err(|err| Error::msg(format!("Invalid storage url {}: {}", value, err)))?;
+        match url.scheme() {
+            "fs" | "memory" => {}
+            "s3" | "sftp" if url.host_str().is_some_and(|host| !host.is_empty()) => {}
+            "s3" | "sftp" => {
+                return Err(Error::msg(format!(
+                    "Storage url {} is missing the {}",
+                    value,
+                    if url.scheme() == "s3" {
+                        "bucket"
+                    } else {
+                        "host"
+                    }
+                )))
+            }
+            scheme => {
+                return Err(Error::msg(format!(
+                    "Unknown storage scheme {}, use fs, s3, sftp or memory",
+                    scheme
+                )))
+            }
+        }
+        Ok(StorageUrl { url })
+    }
+
+    pub fn from_path(path: &Path) -> Result<StorageUrl> {
+        let url = Url::from_file_path(path::absolute(path)?)
+            .map_err(|_| Error::msg(format!("Invalid storage path {}", path.display())))?;
+        Ok(StorageUrl {
+            url: Url::parse(&url.as_str().replacen("file://", "fs://", 1))?,
+        })
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        match self.url.scheme() {
+            "s3" => Scheme::S3,
+            "sftp" => Scheme::Sftp,
+            "memory" => Scheme::Memory,
+            _ => Scheme::Fs,
+        }
+    }
+
+    // memory storage is lost when the process exits
+    pub fn is_persistent(&self) -> bool {
+        self.scheme() != Scheme::Memory
+    }
+
+    fn root(&self) -> String {
+        // decodes escaped characters of local paths
+        if let (Scheme::Fs, Ok(path)) = (self.scheme(), self.url.to_file_path()) {
+            return path.display().to_string();
+        }
+        match self.url.path() {
+            "" => "/".to_string(),
+            root => root.to_string(),
+        }
+    }
+
+    // credentials are not part of the url, s3 reads them from the AWS_* variables or ~/.aws,
+    // sftp uses the ssh agent, ~/.ssh/config or the key file in HOARD_SFTP_KEY
+    pub fn operator_config(&self) -> Vec<(String, String)> {
+        let mut config = vec![("root".to_string(), self.root())];
+        match self.scheme() {
+            Scheme::S3 => {
+                config.push((
+                    "bucket".to_string(),
+                    self.url.host_str().unwrap_or_default().to_string(),
+                ));
+            }
+            Scheme::Sftp => {
+                let endpoint = match self.url.port() {
+                    Some(port) => format!("{}:{}", self.url.host_str().unwrap_or_default(), port),
+                    None => self.url.host_str().unwrap_or_default().to_string(),
+                };
+                config.push(("endpoint".to_string(), endpoint));
+                if !self.url.username().is_empty() {
+                    config.push(("user".to_string(), self.url.username().to_string()));
+                }
+                if let Ok(key) = env::var(Self::SFTP_KEY_VARIABLE) {
+                    config.push(("key".to_string(), key));
+                }
+            }
+            _ => {}
+        }
+        // e.g. ?region=eu-central-1&endpoint=http://localhost:9000 for s3 compatible stores
+        config.extend(
+            self.url
+                .query_pairs()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        config
+    }
+}
+
+impl FromStr for StorageUrl {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Self::parse(value)
+    }
+}
+
+impl TryFrom<String> for StorageUrl {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::parse(&value)
+    }
+}
+
+impl From<StorageUrl> for String {
+    fn from(storage_url: StorageUrl) -> Self {
+        storage_url.to_string()
+    }
+}
+
+impl Display for StorageUrl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_value(storage_url: &StorageUrl, key: &str) -> Option<String> {
+        storage_url
+            .operator_config()
+            .into_iter()
+            .find(|(config_key, _)| config_key == key)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn storage_url_parse() {
+        let storage_url = StorageUrl::parse("fs:///var/backups").unwrap();
+        assert_eq!(storage_url.scheme(), Scheme::Fs);
+        assert_eq!(config_value(&storage_url, "root").unwrap(), "/var/backups");
+
+        let storage_url =
+            StorageUrl::parse("s3://backups/hoard?region=eu-central-1&endpoint=http://minio:9000")
+                .unwrap();
+        assert_eq!(storage_url.scheme(), Scheme::S3);
+        assert_eq!(config_value(&storage_url, "bucket").unwrap(), "backups");
+        assert_eq!(config_value(&storage_url, "root").unwrap(), "/hoard");
+        assert_eq!(
+            config_value(&storage_url, "endpoint").unwrap(),
+            "http://minio:9000"
+        );
+
+        let storage_url = StorageUrl::parse("sftp://hoard@backup.local:2222/srv").unwrap();
+        assert_eq!(storage_url.scheme(), Scheme::Sftp);
+        assert_eq!(
+            config_value(&storage_url, "endpoint").unwrap(),
+            "backup.local:2222"
+        );
+        assert_eq!(config_value(&storage_url, "user").unwrap(), "hoard");
+
+        let storage_url = StorageUrl::parse("memory://").unwrap();
+        assert_eq!(storage_url.scheme(), Scheme::Memory);
+        assert_eq!(config_value(&storage_url, "root").unwrap(), "/");
+        assert!(!storage_url.is_persistent());
+
+        assert!(StorageUrl::parse("s3:///prefix").is_err());
+        assert!(StorageUrl::parse("ftp://host/path").is_err());
+    }
+
+    #[test]
+    fn storage_url_from_path() {
+        let storage_url = StorageUrl::parse("/var/backups").unwrap();
+        assert_eq!(storage_url.to_string(), "fs:///var/backups");
+        assert_eq!(
+            StorageUrl::parse(&storage_url.to_string()).unwrap(),
+            storage_url
+        );
+    }
+}
@@ -1,13 +1,21 @@
+use crate::backup::models::file_attributes::FileAttributes;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Symlink {
     pub from: String,
+    // the link target as stored in the link, it may not exist
     pub to: String,
+    #[serde(default)]
+    pub attributes: Option<FileAttributes>,
 }
 
 impl Symlink {
-    pub fn new<'a>(from: String, to: String) -> Symlink {
-        Symlink { from, to }
+    pub fn new(from: String, to: String) -> Symlink {
+        Symlink {
+            from,
+            to,
+            attributes: None,
+        }
     }
 }
@@ -0,0 +1,140 @@
+use crate::backup::models::backup_metadata::SerializationType;
+use crate::backup::models::lib::{deserialize_from_file, serialize_to_new_file};
+use crate::backup::models::repository_key::RepositoryKey;
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::stream::{Decoder, Encoder};
+
+// trained on a sample of the chunks of a repository, improves the compression of
+// small chunks, dictionaries are never changed or deleted, chunks name theirs by id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ZstdDictionary {
+    // starts at 1, 0 in a chunk header means no dictionary
+    pub id: u32,
+    pub data: Vec<u8>,
+}
+
+impl ZstdDictionary {
+    const DICTIONARIES_DIRECTORY: &'static str = "dictionaries";
+
+    pub fn train<S: AsRef<[u8]>>(id: u32, samples: &[S], max_size: usize) -> Result<Self> {
+        let data = zstd::dict::from_samples(samples, max_size).map_err(|err| {
+            Error::msg(format!(
+                "Could not train a dictionary from {} samples: {}",
+                samples.len(),
+                err
+            ))
+        })?;
+        Ok(ZstdDictionary { id, data })
+    }
+
+    // prepared once, loading a dictionary is expensive
+    pub fn encoder_dictionary(&self, level: i32) -> EncoderDictionary<'static> {
+        EncoderDictionary::copy(&self.data, level)
+    }
+
+    pub fn decoder_dictionary(&self) -> DecoderDictionary<'static> {
+        DecoderDictionary::copy(&self.data)
+    }
+
+    pub fn compress(data: &[u8], encoder_dictionary: &EncoderDictionary) -> Result<Vec<u8>> {
+        let mut encoder = Encoder::with_prepared_dictionary(Vec::new(), encoder_dictionary)?;
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn decompress(data: &[u8], decoder_dictionary: &DecoderDictionary) -> Result<Vec<u8>> {
+        let mut decompressed_data = Vec::new();
+        Decoder::with_prepared_dictionary(data, decoder_dictionary)?
+            .read_to_end(&mut decompressed_data)?;
+        Ok(decompressed_data)
+    }
+
+    pub fn path(directory_path: &Path, id: u32) -> PathBuf {
+        directory_path
+            .join(Self::DICTIONARIES_DIRECTORY)
+            .join(id.to_string())
+    }
+
+    pub fn serialize(
+        &self,
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<()> {
+        fs::create_dir_all(directory_path.join(Self::DICTIONARIES_DIRECTORY))?;
+        serialize_to_new_file(
+            &Self::path(directory_path, self.id),
+            self,
+            SerializationType::MessagePack,
+            repository_key,
+            format!("dictionary:{}", self.id).as_bytes(),
+        )
+    }
+
+    // all dictionaries of the repository, ordered by id
+    pub fn list(
+        directory_path: &Path,
+        repository_key: Option<&RepositoryKey>,
+    ) -> Result<Vec<ZstdDictionary>> {
+        let dictionaries_path = directory_path.join(Self::DICTIONARIES_DIRECTORY);
+        if !dictionaries_path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut dictionaries = Vec::new();
+        for entry in fs::read_dir(dictionaries_path)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let dictionary: ZstdDictionary = deserialize_from_file(
+                &path,
+                repository_key,
+                format!("dictionary:{}", file_name).as_bytes(),
+            )?;
+            if path.file_name() != Some(dictionary.id.to_string().as_ref()) {
+                return Err(Error::msg(format!(
+                    "{} contains dictionary {}",
+                    path.display(),
+                    dictionary.id
+                )));
+            }
+            dictionaries.push(dictionary);
+        }
+        dictionaries.sort_by_key(|dictionary| dictionary.id);
+        Ok(dictionaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_dictionary_train() {
+        let samples: Vec<Vec<u8>> = (0..500)
+            .map(|index| {
+                format!(
+                    r#"{{"id": {}, "name": "user {}", "email": "user{}@example.com", "active": {}}}"#,
+                    index,
+                    index,
+                    index,
+                    index % 2 == 0
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dictionary = ZstdDictionary::train(1, &samples, 4096).unwrap();
+
+        let compressed_data =
+            ZstdDictionary::compress(&samples[42], &dictionary.encoder_dictionary(3)).unwrap();
+        assert!(compressed_data.len() < zstd::encode_all(&samples[42][..], 3).unwrap().len());
+        assert_eq!(
+            ZstdDictionary::decompress(&compressed_data, &dictionary.decoder_dictionary()).unwrap(),
+            samples[42]
+        );
+
+        assert!(ZstdDictionary::train(2, &samples[..1], 4096).is_err());
+    }
+}
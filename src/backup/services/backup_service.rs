@@ -1,5 +1,12 @@
 use anyhow::Result;
-use log::{debug, info};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use rayon::ThreadPoolBuilder;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::sync::mpsc;
 use std::sync::Arc;
 use std::time::Instant;
 use std::{
@@ -10,19 +17,42 @@ use walkdir::WalkDir;
 
 use crate::backup::models::backup_config::BackupConfig;
 use crate::backup::models::backup_metadata::{BackupMetadata, FileMetadataMap, SerializationType};
+use crate::backup::models::backup_options::BackupOptions;
 use crate::backup::models::chunk::Chunk;
+use crate::backup::models::chunk_index::ChunkIndex;
+use crate::backup::models::directory::Directory;
+use crate::backup::models::extended_attributes::{ExtendedAttributes, ExtendedAttributesMap};
+use crate::backup::models::file_attributes::FileAttributes;
+use crate::backup::models::file_metadata::FileMetadata;
+use crate::backup::models::hardlink_group::HardlinkGroup;
+use crate::backup::models::lib::{group_name, user_name};
+use crate::backup::models::repository_config::RepositoryConfig;
+use crate::backup::models::snapshot::Snapshot;
+use crate::backup::models::special_file::{SpecialFile, SpecialFileType};
 use crate::backup::models::symlink::Symlink;
 use crate::backup::services::chunk_storage::ChunkStorage;
 use crate::backup::services::file_chunker::FileChunker;
+use crate::backup::services::path_filter::PathFilter;
 
 pub struct BackupService {
     backup_config: Arc<BackupConfig>,
     file_chunker: Arc<FileChunker>,
     chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    backup_options: BackupOptions,
 
     symlinks: Vec<Symlink>,
+    directories: Vec<Directory>,
+    special_files: Vec<SpecialFile>,
+    // (device, inode) -> paths of files with more than one link
+    hardlink_groups: HashMap<(u64, u64), HardlinkGroup>,
     // filepath -> FileMetadata
     file_metadata_map: FileMetadataMap,
+    // filepath -> FileMetadata of the latest snapshot of the same source
+    parent_file_metadata_map: FileMetadataMap,
+    // uid/gid -> name, looked up once per backup
+    user_names: HashMap<u32, Option<String>>,
+    group_names: HashMap<u32, Option<String>>,
+    extended_attributes: ExtendedAttributesMap,
 }
 
 impl BackupService {
@@ -30,129 +60,348 @@ impl BackupService {
         backup_config: Arc<BackupConfig>,
         file_chunker: Arc<FileChunker>,
         chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+        backup_options: BackupOptions,
     ) -> BackupService {
         BackupService {
             backup_config,
             file_chunker,
             chunk_storage,
+            backup_options,
             symlinks: Default::default(),
+            directories: Default::default(),
+            special_files: Default::default(),
+            hardlink_groups: Default::default(),
             file_metadata_map: Default::default(),
+            parent_file_metadata_map: Default::default(),
+            user_names: Default::default(),
+            group_names: Default::default(),
+            extended_attributes: Default::default(),
         }
     }
 
+    fn file_attributes(&mut self, path: &Path, metadata: &Metadata) -> Result<FileAttributes> {
+        let user = self
+            .user_names
+            .entry(metadata.uid())
+            .or_insert_with(|| user_name(metadata.uid()))
+            .clone();
+        let group = self
+            .group_names
+            .entry(metadata.gid())
+            .or_insert_with(|| group_name(metadata.gid()))
+            .clone();
+        let mut file_attributes = FileAttributes::new(metadata, user, group);
+
+        if let Some(extended_attributes) = ExtendedAttributes::read(path)? {
+            let id = extended_attributes.id();
+            self.extended_attributes
+                .entry(id.clone())
+                .or_insert(extended_attributes);
+            file_attributes.extended_attributes_id = Some(id);
+        }
+        Ok(file_attributes)
+    }
+
+    // the chunks of the parent snapshot are reused for files that did not change since
+    fn unchanged_file_metadata(
+        &self,
+        path: &str,
+        file_attributes: &FileAttributes,
+    ) -> Result<Option<FileMetadata>> {
+        if self.backup_options.force_rehash {
+            return Ok(None);
+        }
+        let Some(parent_file_metadata) = self.parent_file_metadata_map.get(path) else {
+            return Ok(None);
+        };
+        let Some(parent_file_attributes) = parent_file_metadata.attributes.as_ref() else {
+            return Ok(None);
+        };
+        if !file_attributes.is_unchanged(parent_file_attributes) {
+            return Ok(None);
+        }
+        for file_chunk in parent_file_metadata.chunks.iter() {
+            if !self.chunk_storage.chunk_exists(&file_chunk.hash)? {
+                return Ok(None);
+            }
+        }
+        debug!("Unchanged: {}", path);
+        Ok(Some(parent_file_metadata.clone()))
+    }
+
     pub fn walk(&mut self) -> Result<()> {
         info!("Walking directory: {}...", self.backup_config.input_path,);
         let start = Instant::now();
 
-        for dir_entry_result in WalkDir::new(&self.backup_config.input_path).into_iter() {
-            let dir_entry = dir_entry_result?;
-            if dir_entry.path().is_dir() {
-                // currently directories are useless for us
-                debug!("skipping directory: {}", dir_entry.path().display());
-                continue;
-            }
+        let mut path_filter = PathFilter::new(
+            Path::new(&self.backup_config.input_path),
+            &self.backup_options,
+        )?;
+        let workers = self
+            .backup_options
+            .workers
+            .filter(|workers| *workers > 0)
+            .unwrap_or_else(num_cpus::get);
+        let thread_pool = ThreadPoolBuilder::new().num_threads(workers).build()?;
+        let (sender, receiver) = mpsc::channel();
+        let mut unchanged_files = 0;
 
-            // TODO: how to backup and restore symlinks? wtf?
-            if dir_entry.path().is_symlink() {
-                self.symlinks.push(Symlink::new(
-                    dir_entry.path().display().to_string(),
-                    fs::read_link(dir_entry.path())?.display().to_string(),
-                ));
-                continue;
+        // the directory is walked on this thread while the pool chunks the files found so far,
+        // chunked files are collected as they are done, so their metadata is not queued up
+        thread_pool.in_place_scope(|scope| -> Result<()> {
+            let mut walker = WalkDir::new(&self.backup_config.input_path).into_iter();
+            while let Some(dir_entry_result) = walker.next() {
+                for file_metadata_result in receiver.try_iter() {
+                    self.add_chunked_file_metadata(file_metadata_result?)?;
+                }
+                let dir_entry = dir_entry_result?;
+                if path_filter.is_excluded(&dir_entry)? {
+                    if dir_entry.file_type().is_dir() {
+                        walker.skip_current_dir();
+                    }
+                    continue;
+                }
+                // links are not followed, a linked input path is backed up as what it points to
+                let metadata = if dir_entry.depth() == 0 {
+                    fs::metadata(dir_entry.path())?
+                } else {
+                    dir_entry.metadata()?
+                };
+                if metadata.is_symlink() {
+                    let mut symlink = Symlink::new(
+                        dir_entry.path().display().to_string(),
+                        fs::read_link(dir_entry.path())?.display().to_string(),
+                    );
+                    symlink.attributes = Some(self.file_attributes(dir_entry.path(), &metadata)?);
+                    self.symlinks.push(symlink);
+                    continue;
+                }
+
+                if metadata.is_dir() {
+                    let mut directory = Directory::new(dir_entry.path().display().to_string());
+                    directory.attributes = Some(self.file_attributes(dir_entry.path(), &metadata)?);
+                    self.directories.push(directory);
+                    continue;
+                }
+
+                // reading a fifo would block, reading a device would back up the device
+                if let Some(file_type) = SpecialFileType::from_metadata(&metadata) {
+                    let mut special_file = SpecialFile::new(
+                        dir_entry.path().display().to_string(),
+                        file_type,
+                        &metadata,
+                    );
+                    special_file.attributes =
+                        Some(self.file_attributes(dir_entry.path(), &metadata)?);
+                    self.special_files.push(special_file);
+                    continue;
+                }
+                if metadata.file_type().is_socket() {
+                    warn!("Skipping socket: {}", dir_entry.path().display());
+                    continue;
+                }
+
+                // every inode is chunked once, further links only reference the first path
+                if metadata.nlink() > 1 {
+                    let path = dir_entry.path().display().to_string();
+                    match self.hardlink_groups.entry((metadata.dev(), metadata.ino())) {
+                        Entry::Occupied(mut entry) => {
+                            debug!("Hardlink: {} -> {}", path, entry.get().path);
+                            entry.get_mut().links.push(path);
+                            continue;
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(HardlinkGroup::new(path));
+                        }
+                    }
+                }
+
+                let file_attributes = self.file_attributes(dir_entry.path(), &metadata)?;
+                if let Some(mut file_metadata) = self.unchanged_file_metadata(
+                    &dir_entry.path().display().to_string(),
+                    &file_attributes,
+                )? {
+                    unchanged_files += 1;
+                    file_metadata.attributes = Some(file_attributes);
+                    self.add_file_metadata(file_metadata)?;
+                    continue;
+                }
+
+                let file_chunker = self.file_chunker.clone();
+                let sender = sender.clone();
+                let path = dir_entry.into_path();
+                scope.spawn(move |_| {
+                    let result = file_chunker.chunk_file(&path).map(|mut file_metadata| {
+                        file_metadata.attributes = Some(file_attributes);
+                        file_metadata
+                    });
+                    // the receiver lives until all files are chunked
+                    sender.send(result).unwrap();
+                });
             }
+            Ok(())
+        })?;
+        drop(sender);
 
-            let file_metadata = self.file_chunker.chunk_file(dir_entry.path())?;
-            if self.file_metadata_map.contains_key(&file_metadata.key())
-                && file_metadata.fingerprint()
-                    != self
-                        .file_metadata_map
-                        .get(&file_metadata.key())
-                        .unwrap()
-                        .fingerprint()
-            {
+        for file_metadata_result in receiver {
+            self.add_chunked_file_metadata(file_metadata_result?)?;
+        }
+
+        info!(
+            "Done walking with {} workers - took {:?}, {} unchanged files were not read",
+            workers,
+            start.elapsed(),
+            unchanged_files
+        );
+        Ok(())
+    }
+
+    fn add_chunked_file_metadata(&mut self, file_metadata: FileMetadata) -> Result<()> {
+        if let Some(parent_file_metadata) = self.parent_file_metadata_map.get(&file_metadata.key())
+        {
+            if file_metadata.fingerprint() != parent_file_metadata.fingerprint() {
                 info!("File {} changed!", file_metadata.key());
-                self.file_metadata_map
-                    .insert(file_metadata.key(), file_metadata.clone());
-            } else {
-                self.file_metadata_map
-                    .insert(file_metadata.key(), file_metadata.clone());
             }
+        }
+        self.add_file_metadata(file_metadata)
+    }
+
+    fn add_file_metadata(&mut self, file_metadata: FileMetadata) -> Result<()> {
+        for file_chunk in file_metadata.chunks.iter() {
+            self.chunk_storage.add_chunk_if_not_exists(Chunk {
+                hash: file_chunk.hash.clone(),
+                length: file_chunk.length,
+            })?;
+        }
+        self.file_metadata_map
+            .insert(file_metadata.key(), file_metadata);
+        Ok(())
+    }
+
+    // the latest snapshot of the same source path on this host
+    fn parent_backup_metadata(&self, snapshot: &Snapshot) -> Result<Option<BackupMetadata>> {
+        BackupMetadata::latest_matching(
+            Path::new(&self.backup_config.output_path),
+            self.backup_config.repository_key.as_ref(),
+            |parent_snapshot| {
+                parent_snapshot.source_path == snapshot.source_path
+                    && parent_snapshot.hostname == snapshot.hostname
+            },
+        )
+    }
+
+    // the first backup stores its parameters in the repository config, later backups
+    // with other parameters still work, but do not deduplicate against existing chunks
+    fn check_chunker_parameters(&self, output_path: &Path) -> Result<()> {
+        let chunker_parameters = self.backup_config.chunker_parameters();
+        chunker_parameters.validate()?;
 
-            for (hash, file_chunk) in file_metadata.chunks {
-                if !self.chunk_storage.chunk_exists(&hash) {
-                    self.chunk_storage.add_chunk(Chunk {
-                        hash: hash.clone(),
-                        length: file_chunk.length,
-                    })?
+        let mut repository_config = RepositoryConfig::deserialize(output_path)?;
+        match repository_config.chunker_parameters {
+            None => {
+                // repositories of older versions store no parameters, their chunks may differ
+                if !BackupMetadata::snapshot_ids(output_path)?.is_empty() {
+                    warn!(
+                        "The repository does not store the chunk sizes of its snapshots, storing {}, data chunked with other sizes will not be deduplicated",
+                        chunker_parameters
+                    );
                 }
+                repository_config.chunker_parameters = Some(chunker_parameters);
+                repository_config.update(output_path)?;
             }
+            Some(repository_chunker_parameters)
+                if repository_chunker_parameters != chunker_parameters =>
+            {
+                warn!(
+                    "Chunking with {}, but the repository was chunked with {}, data will not be deduplicated against existing chunks",
+                    chunker_parameters, repository_chunker_parameters
+                );
+            }
+            Some(_) => {}
         }
-
-        info!("Done walking - took {:?}", start.elapsed());
         Ok(())
     }
 
-    pub fn backup(&mut self) -> Result<()> {
-        let old_backup_metadata =
-            BackupMetadata::deserialize(Path::new(&self.backup_config.output_path))?;
-        self.symlinks = old_backup_metadata.symlinks;
-        self.file_metadata_map = old_backup_metadata.file_metadata_map;
-        self.chunk_storage
-            .load_chunk_map(old_backup_metadata.chunk_map)?;
-        self.walk()?;
+    pub fn backup(&mut self) -> Result<Snapshot> {
+        let backup_config = self.backup_config.clone();
+        let output_path = Path::new(&backup_config.output_path);
+        let snapshot = Snapshot::new(self.backup_config.input_path.clone());
+        self.check_chunker_parameters(output_path)?;
+        RepositoryConfig::upgrade(output_path)?;
 
-        // let old_backup_metadata =
-        //     BackupMetadata::deserialize(Path::new(&self.backup_config.output_path))?;
-
-        // for (file_path, file_metadata) in self.file_metadata_map.iter() {
-        //     if let Some(old_file_metadata) = old_backup_metadata.file_metadata_map.get(file_path) {
-        //         if file_metadata.fingerprint() != old_file_metadata.fingerprint() {
-        //             info!("Files: {} are not identical", file_path);
-        //
-        //             let chunks: Vec<_> = file_metadata
-        //                 .chunks
-        //                 .keys()
-        //                 .filter(|key| !old_file_metadata.chunks.contains_key(*key))
-        //                 .collect();
-        //
-        //             info!("New chunks: {:?}", chunks);
-        //         } else {
-        //             // info!("File: {} are identical", file_path);
-        //         }
-        //     }
-        // }
+        if let Some(parent_backup_metadata) = self.parent_backup_metadata(&snapshot)? {
+            info!(
+                "Using snapshot {} as parent",
+                parent_backup_metadata.snapshot.short_id()
+            );
+            self.parent_file_metadata_map = parent_backup_metadata.file_metadata_map;
+        }
+        if !self.chunk_storage.has_chunk_map()? {
+            self.chunk_storage.load_chunk_map(
+                ChunkIndex::deserialize(output_path, backup_config.repository_key.as_ref())?
+                    .chunk_map,
+            )?;
+        }
+        self.walk()?;
 
         info!(
             "Writing backup metadata to: {}...",
             self.backup_config.output_path
         );
 
+        // the index is written first, so a snapshot never references unknown chunks,
+        // the chunks of this backup are added to it, chunks other hosts added are kept
+        self.chunk_storage.flush()?;
+        let mut chunk_index =
+            ChunkIndex::deserialize(output_path, backup_config.repository_key.as_ref())?;
+        chunk_index
+            .chunk_map
+            .extend(self.chunk_storage.added_chunks()?);
+        chunk_index.serialize(
+            output_path,
+            SerializationType::MessagePack,
+            backup_config.repository_key.as_ref(),
+        )?;
         let backup_metadata = BackupMetadata::new_with_data(
-            self.chunk_storage.chunk_map()?,
+            snapshot.clone(),
             self.file_metadata_map.clone(),
-            self.symlinks.clone().clone(),
+            self.symlinks.clone(),
+            self.directories.clone(),
+            self.hardlink_groups
+                .values()
+                .filter(|hardlink_group| !hardlink_group.links.is_empty())
+                .cloned()
+                .sorted_by(|a, b| Ord::cmp(&a.path, &b.path))
+                .collect(),
+            self.extended_attributes.clone(),
+            self.special_files.clone(),
         );
         backup_metadata.serialize(
-            Path::new(&self.backup_config.output_path),
+            output_path,
             SerializationType::MessagePack,
+            backup_config.repository_key.as_ref(),
         )?;
 
         info!(
-            "Done writing backup metadata to: {}",
+            "Done writing snapshot {} to: {}",
+            snapshot.short_id(),
             self.backup_config.output_path
         );
         info!(
-            "Read: {} MB",
+            "Stored: {} MB of new chunks",
             self.chunk_storage
-                .chunk_map()?
+                .added_chunks()?
                 .values()
                 .map(|value| value.length)
-                .reduce(|a, b| a + b)
-                .unwrap()
+                .sum::<usize>()
                 / 1024
                 / 1024
         );
+        info!(
+            "Compression: {}",
+            self.file_chunker.compression_statistics()
+        );
 
-        Ok(())
+        Ok(snapshot)
     }
 }
@@ -0,0 +1,178 @@
+use crate::backup::models::backup_config::BackupConfig;
+use crate::backup::models::backup_metadata::BackupMetadata;
+use crate::backup::models::chunk::Chunk;
+use crate::backup::models::chunk_index::ChunkIndex;
+use crate::backup::services::chunk_storage::ChunkStorage;
+use anyhow::Result;
+use itertools::Itertools;
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Default, Clone)]
+pub struct CheckReport {
+    pub checked_snapshots: usize,
+    pub damaged_snapshots: Vec<String>,
+    // referenced by a snapshot, but not in the chunk index
+    pub unindexed_chunks: Vec<String>,
+    // referenced by a snapshot, but not in the storage
+    pub missing_chunks: Vec<String>,
+    // could not be read or do not match their hash
+    pub corrupt_chunks: Vec<String>,
+    // stored or indexed, but not referenced by any snapshot
+    pub orphaned_chunks: Vec<String>,
+    pub read_chunks: usize,
+}
+
+impl CheckReport {
+    // orphaned chunks only waste space, they do not break restores
+    pub fn is_ok(&self) -> bool {
+        self.damaged_snapshots.is_empty()
+            && self.unindexed_chunks.is_empty()
+            && self.missing_chunks.is_empty()
+            && self.corrupt_chunks.is_empty()
+    }
+}
+
+pub struct CheckService {
+    backup_config: Arc<BackupConfig>,
+    chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+}
+
+impl CheckService {
+    pub fn new(
+        backup_config: Arc<BackupConfig>,
+        chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    ) -> CheckService {
+        CheckService {
+            backup_config,
+            chunk_storage,
+        }
+    }
+
+    // read_data_percentage: share of the referenced chunks (0-100) that are read
+    // and verified against their hash, None only checks the structure
+    pub fn check(&mut self, read_data_percentage: Option<f64>) -> Result<CheckReport> {
+        info!("Checking repository: {}...", self.backup_config.input_path);
+        let start = Instant::now();
+        let input_path = Path::new(&self.backup_config.input_path);
+        let repository_key = self.backup_config.repository_key.as_ref();
+        let mut check_report = CheckReport::default();
+
+        let mut referenced_chunks = HashSet::new();
+        for snapshot_id in BackupMetadata::snapshot_ids(input_path)? {
+            match BackupMetadata::deserialize(input_path, &snapshot_id, repository_key) {
+                Ok(backup_metadata) => {
+                    for file_metadata in backup_metadata.file_metadata_map.values() {
+                        for file_chunk in file_metadata.chunks.iter() {
+                            referenced_chunks.insert(file_chunk.hash.clone());
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Snapshot {} could not be decoded: {}", snapshot_id, err);
+                    check_report.damaged_snapshots.push(snapshot_id);
+                }
+            }
+            check_report.checked_snapshots += 1;
+        }
+
+        let chunk_map = ChunkIndex::deserialize(input_path, repository_key)?.chunk_map;
+        let stored_chunks: HashSet<String> =
+            self.chunk_storage.stored_chunks()?.into_iter().collect();
+
+        for hash in referenced_chunks.iter().sorted() {
+            if !chunk_map.contains_key(hash) {
+                error!("Chunk {} is not in the chunk index", hash);
+                check_report.unindexed_chunks.push(hash.clone());
+            }
+            if !stored_chunks.contains(hash) {
+                error!("Chunk {} is missing in the storage", hash);
+                check_report.missing_chunks.push(hash.clone());
+            }
+        }
+
+        check_report.orphaned_chunks = chunk_map
+            .keys()
+            .chain(stored_chunks.iter())
+            .filter(|hash| !referenced_chunks.contains(*hash))
+            .unique()
+            .cloned()
+            .collect();
+        if !check_report.orphaned_chunks.is_empty() {
+            warn!(
+                "{} chunks are not referenced by any snapshot, run prune to delete them",
+                check_report.orphaned_chunks.len()
+            );
+        }
+
+        if let Some(read_data_percentage) = read_data_percentage {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .as_nanos()
+                .to_le_bytes();
+            let chunk_id_key = self.backup_config.chunk_id_key()?;
+
+            for hash in referenced_chunks
+                .iter()
+                .filter(|hash| stored_chunks.contains(*hash))
+                .filter(|hash| Self::is_selected(&seed, hash, read_data_percentage))
+            {
+                check_report.read_chunks += 1;
+                if !self.verify_chunk(hash, chunk_id_key.as_ref()) {
+                    check_report.corrupt_chunks.push(hash.clone());
+                }
+            }
+        }
+
+        info!(
+            "Checked {} snapshots and {} chunks ({} read) - took {:?}",
+            check_report.checked_snapshots,
+            referenced_chunks.len(),
+            check_report.read_chunks,
+            start.elapsed()
+        );
+        if check_report.is_ok() {
+            info!("No errors were found");
+        } else {
+            error!(
+                "{} damaged snapshots, {} unindexed, {} missing and {} corrupt chunks",
+                check_report.damaged_snapshots.len(),
+                check_report.unindexed_chunks.len(),
+                check_report.missing_chunks.len(),
+                check_report.corrupt_chunks.len()
+            );
+        }
+        Ok(check_report)
+    }
+
+    // picks a different random subset on every run, --read-data selects every chunk
+    fn is_selected(seed: &[u8], hash: &str, read_data_percentage: f64) -> bool {
+        if read_data_percentage >= 100.0 {
+            return true;
+        }
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(seed);
+        hasher.update(hash.as_bytes());
+        let bytes = hasher.finalize();
+        let sample = u32::from_le_bytes(bytes.as_bytes()[0..4].try_into().unwrap());
+        (sample as f64 / u32::MAX as f64) * 100.0 < read_data_percentage
+    }
+
+    fn verify_chunk(&self, hash: &str, chunk_id_key: Option<&[u8; 32]>) -> bool {
+        debug!("Reading chunk: {}", hash);
+        match self.chunk_storage.load_chunk(hash) {
+            Ok(data) if Chunk::hash(&data, chunk_id_key) == hash => true,
+            Ok(_) => {
+                error!("Chunk {} does not match its hash", hash);
+                false
+            }
+            Err(err) => {
+                error!("Chunk {} could not be read: {}", hash, err);
+                false
+            }
+        }
+    }
+}
@@ -1,38 +1,339 @@
-use crate::backup::models::lib::split_hash_as_path;
-use anyhow::Result;
-use opendal::layers::{LoggingLayer, RetryLayer};
-use opendal::services::Fs;
-use opendal::{BlockingOperator, Operator};
+use crate::backup::models::backup_metadata::SerializationType;
+use crate::backup::models::chunk_header::ChunkHeader;
+use crate::backup::models::compressibility::{data_skip_reason, SkipReason};
+use crate::backup::models::compression::Compression;
+use crate::backup::models::lib::{
+    deserialize_from_bytes, serialize_to_bytes, split_hash, split_hash_as_path,
+};
+use crate::backup::models::pack_index::PackFileIndex;
+use crate::backup::models::repository_key::RepositoryKey;
+use crate::backup::models::storage_url::StorageUrl;
+use crate::backup::models::zstd_dictionary::ZstdDictionary;
+use anyhow::{Error, Result};
+use opendal::layers::{BlockingLayer, LoggingLayer, RetryLayer};
+use opendal::{BlockingOperator, EntryMode, Operator};
+use std::collections::HashMap;
+use std::ops::Range;
 use std::path::Path;
+use std::sync::OnceLock;
+use tokio::runtime::Runtime;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
 
-pub struct ChunkReaderWriter {}
+// drives the services without a blocking api, like s3 and sftp
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+pub struct ChunkReaderWriter {
+    operator: BlockingOperator,
+    // chunks are encrypted after compression if set
+    repository_key: Option<RepositoryKey>,
+    // of written chunks, read chunks name theirs in the header
+    compression: Compression,
+    // zstd compresses written chunks with it if set
+    encoder_dictionary: Option<(u32, EncoderDictionary<'static>)>,
+    // by id, all dictionaries of the repository
+    decoder_dictionaries: HashMap<u32, DecoderDictionary<'static>>,
+}
 
 impl ChunkReaderWriter {
-    pub fn new() -> ChunkReaderWriter {
-        ChunkReaderWriter {}
+    const PACKS_DIRECTORY: &'static str = "packs";
+    const PACK_EXTENSION: &'static str = "pack";
+    const PACK_INDEX_EXTENSION: &'static str = "index";
+
+    pub fn new(
+        storage_url: &StorageUrl,
+        repository_key: Option<RepositoryKey>,
+        compression: Compression,
+    ) -> Result<ChunkReaderWriter> {
+        Ok(ChunkReaderWriter {
+            operator: Self::build_operator(storage_url)?,
+            repository_key,
+            compression,
+            encoder_dictionary: None,
+            decoder_dictionaries: HashMap::new(),
+        })
     }
 
-    pub fn build_operator(&self) -> Result<BlockingOperator> {
-        let builder = Fs::default().root("./");
+    // dictionary_id selects the dictionary written chunks are compressed with
+    pub fn with_dictionaries(
+        mut self,
+        dictionaries: &[ZstdDictionary],
+        dictionary_id: Option<u32>,
+    ) -> Result<Self> {
+        if let (Some(dictionary_id), Compression::Zstd { level }) =
+            (dictionary_id, self.compression)
+        {
+            let dictionary = dictionaries
+                .iter()
+                .find(|dictionary| dictionary.id == dictionary_id)
+                .ok_or_else(|| {
+                    Error::msg(format!("Dictionary {} does not exist", dictionary_id))
+                })?;
+            self.encoder_dictionary = Some((dictionary_id, dictionary.encoder_dictionary(level)));
+        }
+        self.decoder_dictionaries = dictionaries
+            .iter()
+            .map(|dictionary| (dictionary.id, dictionary.decoder_dictionary()))
+            .collect();
+        Ok(self)
+    }
 
-        Ok(Operator::new(builder)?
+    pub fn build_operator(storage_url: &StorageUrl) -> Result<BlockingOperator> {
+        let operator = Operator::via_iter(storage_url.scheme(), storage_url.operator_config())
+            .map_err(|err| Error::msg(format!("Could not open {}: {}", storage_url, err)))?
             .layer(LoggingLayer::default())
-            .layer(RetryLayer::new())
-            .finish()
-            .blocking())
+            .layer(RetryLayer::new());
+        if operator.info().full_capability().blocking {
+            return Ok(operator.blocking());
+        }
+
+        if RUNTIME.get().is_none() {
+            let _ = RUNTIME.set(Runtime::new()?);
+        }
+        let _guard = RUNTIME.get().unwrap().enter();
+        Ok(operator.layer(BlockingLayer::create()?).blocking())
     }
 
-    pub fn write_chunk(&self, hash: &str, data: &Vec<u8>, directory_path: &Path) -> Result<()> {
-        let operator = self.build_operator()?;
-        let file_path = split_hash_as_path(directory_path, hash.to_string());
-        let compressed_data = zstd::encode_all(data.as_slice(), 1)?;
-        Ok(operator.write(file_path.to_str().unwrap(), compressed_data)?)
+    // paths are relative to the root of the storage
+    fn chunk_path(hash: &str, directory_path: &Path) -> String {
+        split_hash_as_path(directory_path, hash.to_string())
+            .display()
+            .to_string()
+    }
+
+    // packs/<first two characters>/<pack id>.<extension>
+    fn pack_path(pack: &str, extension: &str, directory_path: &Path) -> String {
+        split_hash_as_path(
+            &directory_path.join(Self::PACKS_DIRECTORY),
+            format!("{}.{}", pack, extension),
+        )
+        .display()
+        .to_string()
+    }
+
+    // the stored form of a chunk, in its own file or inside a pack, incompressible
+    // data is stored raw, the reason for it is returned
+    pub fn encode_chunk(
+        &self,
+        hash: &str,
+        data: &[u8],
+        mut skip_reason: Option<SkipReason>,
+    ) -> Result<(Vec<u8>, Option<SkipReason>)> {
+        if self.compression == Compression::None {
+            skip_reason = None;
+        } else if skip_reason.is_none() {
+            skip_reason = data_skip_reason(&self.compression, data)?;
+        }
+
+        let mut compressed_data = match skip_reason {
+            Some(_) => None,
+            None => Some(self.compress(data)?),
+        };
+        if self.compression != Compression::None
+            && compressed_data
+                .as_ref()
+                .is_some_and(|(_, compressed_data)| compressed_data.len() >= data.len())
+        {
+            skip_reason = Some(SkipReason::NotSmaller);
+            compressed_data = None;
+        }
+        let mut encoded_data = match compressed_data {
+            Some((chunk_header, compressed_data)) => {
+                let mut encoded_data = chunk_header.encode().to_vec();
+                encoded_data.extend(compressed_data);
+                encoded_data
+            }
+            None => {
+                let mut encoded_data = ChunkHeader::stored_raw().encode().to_vec();
+                encoded_data.extend(data);
+                encoded_data
+            }
+        };
+
+        if let Some(repository_key) = &self.repository_key {
+            // the hash is authenticated, so chunks cannot be swapped
+            encoded_data = repository_key.encrypt(&encoded_data, hash.as_bytes())?;
+        }
+        Ok((encoded_data, skip_reason))
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<(ChunkHeader, Vec<u8>)> {
+        Ok(match &self.encoder_dictionary {
+            Some((dictionary_id, encoder_dictionary)) => (
+                ChunkHeader::with_dictionary(*dictionary_id),
+                ZstdDictionary::compress(data, encoder_dictionary)?,
+            ),
+            None => (
+                ChunkHeader::new(&self.compression),
+                self.compression.compress(data)?,
+            ),
+        })
+    }
+
+    pub fn decode_chunk(&self, hash: &str, mut compressed_data: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(repository_key) = &self.repository_key {
+            compressed_data = repository_key
+                .decrypt(&compressed_data, hash.as_bytes())
+                .map_err(|err| Error::msg(format!("Chunk {}: {}", hash, err)))?;
+        }
+        let (chunk_header, compressed_data) = ChunkHeader::decode(&compressed_data)?;
+        if chunk_header.dictionary_id != 0 {
+            let decoder_dictionary = self
+                .decoder_dictionaries
+                .get(&chunk_header.dictionary_id)
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "Chunk {} was compressed with dictionary {}, which is not in the repository",
+                        hash, chunk_header.dictionary_id
+                    ))
+                })?;
+            return ZstdDictionary::decompress(compressed_data, decoder_dictionary);
+        }
+        Compression::decompress(chunk_header.compression_id, compressed_data)
+    }
+
+    pub fn write_chunk(&self, hash: &str, data: &[u8], directory_path: &Path) -> Result<()> {
+        let file_path = Self::chunk_path(hash, directory_path);
+        Ok(self
+            .operator
+            .write(&file_path, self.encode_chunk(hash, data, None)?.0)?)
     }
 
     pub fn read_chunk(&self, hash: &str, directory_path: &Path) -> Result<Vec<u8>> {
-        let operator = self.build_operator()?;
-        let file_path = split_hash_as_path(directory_path, hash.to_string());
-        let compressed_data = operator.read(file_path.to_str().unwrap())?.to_vec();
-        Ok(zstd::decode_all(compressed_data.as_slice())?.to_vec())
+        let file_path = Self::chunk_path(hash, directory_path);
+        self.decode_chunk(hash, self.operator.read(&file_path)?.to_vec())
+    }
+
+    // the pack is written before its index, so an index never references a missing pack
+    pub fn write_pack(
+        &self,
+        pack: &str,
+        data: Vec<u8>,
+        pack_file_index: &PackFileIndex,
+        directory_path: &Path,
+    ) -> Result<()> {
+        self.operator.write(
+            &Self::pack_path(pack, Self::PACK_EXTENSION, directory_path),
+            data,
+        )?;
+        self.write_pack_file_index(pack, pack_file_index, directory_path)
+    }
+
+    pub fn write_pack_file_index(
+        &self,
+        pack: &str,
+        pack_file_index: &PackFileIndex,
+        directory_path: &Path,
+    ) -> Result<()> {
+        Ok(self.operator.write(
+            &Self::pack_path(pack, Self::PACK_INDEX_EXTENSION, directory_path),
+            serialize_to_bytes(
+                pack_file_index,
+                SerializationType::MessagePack,
+                self.repository_key.as_ref(),
+                format!("pack-index:{}", pack).as_bytes(),
+            )?,
+        )?)
+    }
+
+    pub fn read_pack_file_index(&self, pack: &str, directory_path: &Path) -> Result<PackFileIndex> {
+        let path = Self::pack_path(pack, Self::PACK_INDEX_EXTENSION, directory_path);
+        deserialize_from_bytes(
+            &self.operator.read(&path)?.to_vec(),
+            self.repository_key.as_ref(),
+            format!("pack-index:{}", pack).as_bytes(),
+        )
+        .map_err(|err| Error::msg(format!("{}: {}", path, err)))
+    }
+
+    // only the requested range is read from the storage
+    pub fn read_pack(
+        &self,
+        pack: &str,
+        range: Range<u64>,
+        directory_path: &Path,
+    ) -> Result<Vec<u8>> {
+        Ok(self
+            .operator
+            .read_with(&Self::pack_path(pack, Self::PACK_EXTENSION, directory_path))
+            .range(range)
+            .call()?
+            .to_vec())
+    }
+
+    // the index is deleted first, so an index never references a missing pack
+    pub fn delete_pack(&self, pack: &str, directory_path: &Path) -> Result<()> {
+        self.operator.delete(&Self::pack_path(
+            pack,
+            Self::PACK_INDEX_EXTENSION,
+            directory_path,
+        ))?;
+        Ok(self
+            .operator
+            .delete(&Self::pack_path(pack, Self::PACK_EXTENSION, directory_path))?)
+    }
+
+    // ids of all packs with an index stored below directory_path
+    pub fn list_packs(&self, directory_path: &Path) -> Result<Vec<String>> {
+        let packs_path = directory_path.join(Self::PACKS_DIRECTORY);
+        let mut packs = Vec::new();
+        for entry in self
+            .operator
+            .list_with(&format!("{}/", packs_path.display()))
+            .recursive(true)
+            .call()?
+        {
+            if let Some(pack) = entry
+                .name()
+                .strip_suffix(&format!(".{}", Self::PACK_INDEX_EXTENSION))
+            {
+                packs.push(pack.to_string());
+            }
+        }
+        Ok(packs)
+    }
+
+    // size of the stored (compressed) chunk
+    pub fn chunk_size(&self, hash: &str, directory_path: &Path) -> Result<u64> {
+        let file_path = Self::chunk_path(hash, directory_path);
+        Ok(self.operator.stat(&file_path)?.content_length())
+    }
+
+    pub fn delete_chunk(&self, hash: &str, directory_path: &Path) -> Result<()> {
+        let file_path = Self::chunk_path(hash, directory_path);
+        Ok(self.operator.delete(&file_path)?)
+    }
+
+    // hashes of all chunks stored below directory_path
+    pub fn list_chunks(&self, directory_path: &Path) -> Result<Vec<String>> {
+        let mut hashes = Vec::new();
+
+        for entry in self
+            .operator
+            .list_with(&format!("{}/", directory_path.display()))
+            .recursive(true)
+            .call()?
+        {
+            if entry.metadata().mode() != EntryMode::FILE {
+                continue;
+            }
+
+            let path = Path::new(entry.path());
+            let (Some(file_name), Some(parent_name)) = (
+                path.file_name().and_then(|name| name.to_str()),
+                path.parent()
+                    .and_then(|parent| parent.file_name())
+                    .and_then(|name| name.to_str()),
+            ) else {
+                continue;
+            };
+
+            if file_name.len() > 2
+                && file_name.chars().all(|c| c.is_ascii_hexdigit())
+                && split_hash(file_name) == parent_name
+            {
+                hashes.push(file_name.to_string());
+            }
+        }
+        Ok(hashes)
     }
 }
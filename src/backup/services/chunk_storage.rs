@@ -1,16 +1,28 @@
 use crate::backup::models::backup_config::BackupConfig;
 use crate::backup::models::chunk::Chunk;
+use crate::backup::models::compressibility::SkipReason;
+use crate::backup::models::pack_index::{PackEntry, PackFileIndex, PackIndex};
+use crate::backup::models::storage_url::StorageUrl;
 use crate::backup::services::chunk_reader_writer::ChunkReaderWriter;
 use anyhow::Result;
-use std::collections::HashMap;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::path::{self, Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 pub type ChunkMap = HashMap<String, Chunk>;
 
+#[derive(Debug, Default)]
+pub struct RepackStatistics {
+    pub repacked_packs: usize,
+    pub reclaimed_bytes: u64,
+}
+
 pub trait ChunkStorage: Send + Sync {
     fn add_chunk(&self, chunk: Chunk) -> Result<()>;
 
-    fn chunk_exists(&self, hash: &str) -> bool;
+    fn chunk_exists(&self, hash: &str) -> Result<bool>;
 
     fn add_chunk_if_not_exists(&self, chunk: Chunk) -> Result<bool>;
 
@@ -18,60 +30,386 @@ pub trait ChunkStorage: Send + Sync {
 
     fn load_chunk_map(&self, chunk_map: ChunkMap) -> Result<()>;
 
-    fn store_chunk(&self, hash: &str, data: &Vec<u8>) -> Result<()>;
+    // true if the chunk map is shared and an earlier run loaded the repository index into it
+    fn has_chunk_map(&self) -> Result<bool>;
+
+    // chunks added since the chunk map was loaded
+    fn added_chunks(&self) -> Result<ChunkMap>;
+
+    // skip_reason is set if the data is known to be incompressible, returns why
+    // the chunk was stored raw, if it was
+    fn store_chunk(
+        &self,
+        hash: &str,
+        data: &[u8],
+        skip_reason: Option<SkipReason>,
+    ) -> Result<Option<SkipReason>>;
 
     fn load_chunk(&self, hash: &str) -> Result<Vec<u8>>;
+
+    fn remove_chunk(&self, hash: &str) -> Result<()>;
+
+    // drops chunks claimed by backups that failed before storing them,
+    // must not run while a backup writes into the same repository
+    fn remove_claims(&self) -> Result<()>;
+
+    fn delete_chunk(&self, hash: &str) -> Result<()>;
+
+    // packed chunks are deleted from the index of their pack and freed by repack
+    fn is_packed(&self, hash: &str) -> Result<bool>;
+
+    fn stored_chunk_size(&self, hash: &str) -> Result<u64>;
+
+    fn stored_chunks(&self) -> Result<Vec<String>>;
+
+    // writes chunks that are still buffered and the changed pack indexes
+    fn flush(&self) -> Result<()>;
+
+    // rewrites packs in which more than max_unused_ratio is taken by deleted chunks,
+    // a dry run counts removed_chunks as deleted, as they are not deleted in it
+    fn repack(
+        &self,
+        max_unused_ratio: f64,
+        removed_chunks: &HashSet<String>,
+        dry_run: bool,
+    ) -> Result<RepackStatistics>;
+}
+
+// chunks collected for the next pack
+#[derive(Default)]
+struct PackBuffer {
+    data: Vec<u8>,
+    entries: Vec<PackEntry>,
 }
 
 pub struct LocalChunkStorage {
-    backup_config: Arc<BackupConfig>,
     chunk_map: Arc<Mutex<ChunkMap>>,
+    added_chunks: Mutex<HashSet<String>>,
+    chunk_reader_writer: ChunkReaderWriter,
+    // chunks are stored in the repository directory, or in the root of its storage url
+    chunk_directory: PathBuf,
+    // loaded on first use
+    pack_index: Mutex<Option<PackIndex>>,
+    pack_buffer: Mutex<PackBuffer>,
+    // packs with deleted chunks, their index is rewritten on flush
+    changed_packs: Mutex<HashSet<String>>,
 }
 
 impl LocalChunkStorage {
-    pub fn new(backup_config: Arc<BackupConfig>) -> Self {
-        LocalChunkStorage {
-            backup_config,
+    pub fn new(backup_config: Arc<BackupConfig>, repository_path: &Path) -> Result<Self> {
+        let storage_url = match &backup_config.storage_url {
+            Some(storage_url) => storage_url.clone(),
+            None => StorageUrl::parse("fs:///")?,
+        };
+        let chunk_reader_writer = ChunkReaderWriter::new(
+            &storage_url,
+            backup_config.repository_key.clone(),
+            backup_config.compression,
+        )?
+        .with_dictionaries(&backup_config.dictionaries, backup_config.dictionary_id)?;
+        let chunk_directory = match &backup_config.storage_url {
+            Some(_) => PathBuf::new(),
+            None => path::absolute(repository_path)?,
+        };
+        Ok(LocalChunkStorage {
             chunk_map: Default::default(),
+            added_chunks: Default::default(),
+            chunk_reader_writer,
+            chunk_directory,
+            pack_index: Default::default(),
+            pack_buffer: Default::default(),
+            changed_packs: Default::default(),
+        })
+    }
+
+    // packs are written once they reach this size, chunks are never split between packs
+    const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+    fn with_pack_index<T>(&self, f: impl FnOnce(&mut PackIndex) -> T) -> Result<T> {
+        let mut pack_index = self.pack_index.lock().unwrap();
+        if pack_index.is_none() {
+            let mut loaded_pack_index = PackIndex::default();
+            for pack in self.chunk_reader_writer.list_packs(&self.chunk_directory)? {
+                loaded_pack_index.add_pack(
+                    &pack,
+                    self.chunk_reader_writer
+                        .read_pack_file_index(&pack, &self.chunk_directory)?,
+                );
+            }
+            *pack_index = Some(loaded_pack_index);
         }
+        Ok(f(pack_index.as_mut().unwrap()))
+    }
+
+    fn pack_entry(&self, hash: &str) -> Result<Option<PackEntry>> {
+        self.with_pack_index(|pack_index| pack_index.get(hash).cloned())
+    }
+
+    // encoded chunks are added as they are, repacking does not decode them
+    fn add_to_pack(&self, hash: &str, encoded_data: Vec<u8>) -> Result<()> {
+        let full_pack_buffer = {
+            let mut pack_buffer = self.pack_buffer.lock().unwrap();
+            let offset = pack_buffer.data.len() as u64;
+            pack_buffer.entries.push(PackEntry {
+                hash: hash.to_string(),
+                pack: String::new(),
+                offset,
+                length: encoded_data.len() as u64,
+            });
+            pack_buffer.data.extend(encoded_data);
+            if pack_buffer.data.len() < Self::PACK_SIZE {
+                return Ok(());
+            }
+            mem::take(&mut *pack_buffer)
+        };
+        self.write_pack(full_pack_buffer)
+    }
+
+    fn write_pack(&self, pack_buffer: PackBuffer) -> Result<()> {
+        let pack = blake3::hash(&pack_buffer.data).to_hex().to_string();
+        let pack_file_index = PackFileIndex {
+            size: pack_buffer.data.len() as u64,
+            entries: pack_buffer
+                .entries
+                .into_iter()
+                .map(|pack_entry| PackEntry {
+                    pack: pack.clone(),
+                    ..pack_entry
+                })
+                .collect(),
+        };
+        debug!(
+            "Writing pack {} with {} chunks",
+            pack,
+            pack_file_index.entries.len()
+        );
+        self.chunk_reader_writer.write_pack(
+            &pack,
+            pack_buffer.data,
+            &pack_file_index,
+            &self.chunk_directory,
+        )?;
+        self.with_pack_index(|pack_index| pack_index.add_pack(&pack, pack_file_index))
     }
 }
 
 impl ChunkStorage for LocalChunkStorage {
     fn add_chunk(&self, chunk: Chunk) -> Result<()> {
+        self.added_chunks.lock().unwrap().insert(chunk.hash.clone());
         self.chunk_map
             .lock()
             .unwrap()
             .insert(chunk.hash.clone(), chunk.clone());
         Ok(())
     }
-    fn chunk_exists(&self, hash: &str) -> bool {
-        self.chunk_map.lock().unwrap().contains_key(hash)
+    fn chunk_exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.chunk_map.lock().unwrap().contains_key(hash))
     }
 
+    // checked and added under one lock, so only one caller gets true for a hash
     fn add_chunk_if_not_exists(&self, chunk: Chunk) -> Result<bool> {
-        if !self.chunk_exists(&chunk.hash) {
-            self.add_chunk(chunk)?;
-            return Ok(true);
+        let mut chunk_map = self.chunk_map.lock().unwrap();
+        if chunk_map.contains_key(&chunk.hash) {
+            return Ok(false);
         }
-
-        Ok(false)
+        self.added_chunks.lock().unwrap().insert(chunk.hash.clone());
+        chunk_map.insert(chunk.hash.clone(), chunk);
+        Ok(true)
     }
     fn chunk_map(&self) -> Result<ChunkMap> {
         Ok(self.chunk_map.lock().unwrap().clone())
     }
 
     fn load_chunk_map(&self, chunk_map: ChunkMap) -> Result<()> {
-        Ok(*self.chunk_map.lock().unwrap() = chunk_map)
+        self.added_chunks.lock().unwrap().clear();
+        *self.chunk_map.lock().unwrap() = chunk_map;
+        Ok(())
+    }
+
+    fn has_chunk_map(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn added_chunks(&self) -> Result<ChunkMap> {
+        let added_chunks = self.added_chunks.lock().unwrap();
+        Ok(self
+            .chunk_map
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(hash, _)| added_chunks.contains(*hash))
+            .map(|(hash, chunk)| (hash.clone(), chunk.clone()))
+            .collect())
     }
 
-    fn store_chunk(&self, hash: &str, data: &Vec<u8>) -> Result<()> {
-        let chunk_reader_writer = ChunkReaderWriter::new();
-        chunk_reader_writer.write_chunk(hash, data, self.backup_config.output_path.as_ref())
+    fn store_chunk(
+        &self,
+        hash: &str,
+        data: &[u8],
+        skip_reason: Option<SkipReason>,
+    ) -> Result<Option<SkipReason>> {
+        let (encoded_data, skip_reason) =
+            self.chunk_reader_writer
+                .encode_chunk(hash, data, skip_reason)?;
+        self.add_to_pack(hash, encoded_data)?;
+        Ok(skip_reason)
     }
 
+    // chunks written before packs existed are stored in their own file
     fn load_chunk(&self, hash: &str) -> Result<Vec<u8>> {
-        let chunk_reader_writer = ChunkReaderWriter::new();
-        chunk_reader_writer.read_chunk(hash, self.backup_config.input_path.as_ref())
+        match self.pack_entry(hash)? {
+            Some(pack_entry) => self.chunk_reader_writer.decode_chunk(
+                hash,
+                self.chunk_reader_writer.read_pack(
+                    &pack_entry.pack,
+                    pack_entry.offset..pack_entry.offset + pack_entry.length,
+                    &self.chunk_directory,
+                )?,
+            ),
+            None => self
+                .chunk_reader_writer
+                .read_chunk(hash, &self.chunk_directory),
+        }
+    }
+
+    fn remove_chunk(&self, hash: &str) -> Result<()> {
+        self.added_chunks.lock().unwrap().remove(hash);
+        self.chunk_map.lock().unwrap().remove(hash);
+        Ok(())
+    }
+
+    fn remove_claims(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // packed chunks are only removed from the index, their space is reclaimed by repack
+    fn delete_chunk(&self, hash: &str) -> Result<()> {
+        match self.with_pack_index(|pack_index| pack_index.remove(hash))? {
+            Some(pack_entry) => {
+                self.changed_packs.lock().unwrap().insert(pack_entry.pack);
+                Ok(())
+            }
+            None => self
+                .chunk_reader_writer
+                .delete_chunk(hash, &self.chunk_directory),
+        }
+    }
+
+    fn is_packed(&self, hash: &str) -> Result<bool> {
+        Ok(self.pack_entry(hash)?.is_some())
+    }
+
+    fn stored_chunk_size(&self, hash: &str) -> Result<u64> {
+        match self.pack_entry(hash)? {
+            Some(pack_entry) => Ok(pack_entry.length),
+            None => self
+                .chunk_reader_writer
+                .chunk_size(hash, &self.chunk_directory),
+        }
+    }
+
+    fn stored_chunks(&self) -> Result<Vec<String>> {
+        let mut hashes = self
+            .chunk_reader_writer
+            .list_chunks(&self.chunk_directory)?;
+        hashes.extend(
+            self.with_pack_index(|pack_index| {
+                pack_index.hashes().cloned().collect::<Vec<String>>()
+            })?,
+        );
+        Ok(hashes)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let pack_buffer = mem::take(&mut *self.pack_buffer.lock().unwrap());
+        if !pack_buffer.entries.is_empty() {
+            self.write_pack(pack_buffer)?;
+        }
+        let changed_packs = mem::take(&mut *self.changed_packs.lock().unwrap());
+        for pack in changed_packs {
+            let pack_file_index =
+                self.with_pack_index(|pack_index| pack_index.pack_file_index(&pack))?;
+            if pack_file_index.entries.is_empty() {
+                debug!("Deleting empty pack {}", pack);
+                self.chunk_reader_writer
+                    .delete_pack(&pack, &self.chunk_directory)?;
+                self.with_pack_index(|pack_index| pack_index.remove_pack(&pack))?;
+            } else {
+                self.chunk_reader_writer.write_pack_file_index(
+                    &pack,
+                    &pack_file_index,
+                    &self.chunk_directory,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // the remaining chunks are copied into new packs, which are written before the old ones are deleted
+    fn repack(
+        &self,
+        max_unused_ratio: f64,
+        removed_chunks: &HashSet<String>,
+        dry_run: bool,
+    ) -> Result<RepackStatistics> {
+        let pack_file_indexes = self.with_pack_index(|pack_index| {
+            pack_index
+                .packs()
+                .into_iter()
+                .map(|pack| {
+                    let mut pack_file_index = pack_index.pack_file_index(&pack);
+                    pack_file_index
+                        .entries
+                        .retain(|pack_entry| !removed_chunks.contains(&pack_entry.hash));
+                    (pack, pack_file_index)
+                })
+                .filter(|(_, pack_file_index)| pack_file_index.unused_ratio() > max_unused_ratio)
+                .collect::<Vec<(String, PackFileIndex)>>()
+        })?;
+
+        let mut repack_statistics = RepackStatistics::default();
+        for (pack, pack_file_index) in pack_file_indexes.iter() {
+            repack_statistics.repacked_packs += 1;
+            repack_statistics.reclaimed_bytes += pack_file_index.size
+                - pack_file_index
+                    .entries
+                    .iter()
+                    .map(|pack_entry| pack_entry.length)
+                    .sum::<u64>();
+            if dry_run {
+                debug!("Would repack {}", pack);
+            }
+        }
+        if dry_run {
+            return Ok(repack_statistics);
+        }
+
+        // empty packs are deleted by the flush
+        self.flush()?;
+        let pack_file_indexes: Vec<&(String, PackFileIndex)> = pack_file_indexes
+            .iter()
+            .filter(|(_, pack_file_index)| !pack_file_index.entries.is_empty())
+            .collect();
+        for (pack, pack_file_index) in pack_file_indexes.iter() {
+            debug!("Repacking {}", pack);
+            for pack_entry in pack_file_index.entries.iter() {
+                self.add_to_pack(
+                    &pack_entry.hash,
+                    self.chunk_reader_writer.read_pack(
+                        pack,
+                        pack_entry.offset..pack_entry.offset + pack_entry.length,
+                        &self.chunk_directory,
+                    )?,
+                )?;
+            }
+        }
+
+        self.flush()?;
+        for (pack, _) in pack_file_indexes.iter() {
+            self.chunk_reader_writer
+                .delete_pack(pack, &self.chunk_directory)?;
+            self.with_pack_index(|pack_index| pack_index.remove_pack(pack))?;
+        }
+        Ok(repack_statistics)
     }
 }
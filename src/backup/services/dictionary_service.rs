@@ -0,0 +1,102 @@
+use crate::backup::models::backup_config::BackupConfig;
+use crate::backup::models::chunk_index::ChunkIndex;
+use crate::backup::models::compression::Compression;
+use crate::backup::models::repository_config::RepositoryConfig;
+use crate::backup::models::zstd_dictionary::ZstdDictionary;
+use crate::backup::services::chunk_storage::ChunkStorage;
+use anyhow::{Error, Result};
+use log::info;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub struct DictionaryService {
+    backup_config: Arc<BackupConfig>,
+    chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+}
+
+impl DictionaryService {
+    // zstd trains on the start of larger chunks only
+    const MAX_SAMPLE_SIZE: usize = 128 * 1024;
+
+    pub fn new(
+        backup_config: Arc<BackupConfig>,
+        chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    ) -> DictionaryService {
+        DictionaryService {
+            backup_config,
+            chunk_storage,
+        }
+    }
+
+    // trains a new dictionary from up to sample_count chunks and compresses
+    // the chunks of later backups with it, existing chunks are not rewritten
+    pub fn train(&self, sample_count: usize, dictionary_size: usize) -> Result<ZstdDictionary> {
+        let input_path = Path::new(&self.backup_config.input_path);
+        let repository_key = self.backup_config.repository_key.as_ref();
+        let repository_config = RepositoryConfig::open(input_path)?;
+        if !matches!(repository_config.compression, Compression::Zstd { .. }) {
+            return Err(Error::msg(format!(
+                "Dictionaries require zstd compression, the repository uses {}",
+                repository_config.compression
+            )));
+        }
+        info!(
+            "Training dictionary for repository: {}...",
+            input_path.display()
+        );
+        let start = Instant::now();
+
+        // chunk ids are hashes, so the first ones in hash order are a random sample
+        let mut hashes: Vec<String> = ChunkIndex::deserialize(input_path, repository_key)?
+            .chunk_map
+            .into_keys()
+            .collect();
+        hashes.sort();
+        let mut samples = Vec::new();
+        for hash in hashes.iter().take(sample_count) {
+            let mut data = self.chunk_storage.load_chunk(hash)?;
+            data.truncate(Self::MAX_SAMPLE_SIZE);
+            samples.push(data);
+        }
+
+        // earlier dictionaries are kept, chunks compressed with them still need them
+        let id = self
+            .backup_config
+            .dictionaries
+            .iter()
+            .map(|dictionary| dictionary.id)
+            .max()
+            .unwrap_or_default()
+            + 1;
+        let dictionary = ZstdDictionary::train(id, &samples, dictionary_size)?;
+        // versions before dictionaries must not write to the repository anymore
+        RepositoryConfig::upgrade(input_path)?;
+        dictionary.serialize(input_path, repository_key)?;
+        let mut repository_config = RepositoryConfig::open(input_path)?;
+        repository_config.dictionary_id = Some(id);
+        repository_config.update(input_path)?;
+
+        info!(
+            "Trained dictionary {} of {} bytes from {} chunks - took {:?}",
+            id,
+            dictionary.data.len(),
+            samples.len(),
+            start.elapsed()
+        );
+        Ok(dictionary)
+    }
+
+    // later backups compress without a dictionary, existing dictionaries are kept
+    pub fn disable(&self) -> Result<()> {
+        let input_path = Path::new(&self.backup_config.input_path);
+        let mut repository_config = RepositoryConfig::open(input_path)?;
+        repository_config.dictionary_id = None;
+        repository_config.update(input_path)?;
+        info!(
+            "Disabled dictionary compression for: {}",
+            input_path.display()
+        );
+        Ok(())
+    }
+}
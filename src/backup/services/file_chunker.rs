@@ -1,20 +1,35 @@
 use crate::backup::models::backup_config::BackupConfig;
 use crate::backup::models::chunk::Chunk;
+use crate::backup::models::compressibility::{
+    file_skip_reason, CompressionStatistics, SkipReason, MAGIC_BYTES_SIZE,
+};
+use crate::backup::models::compression::Compression;
 use crate::backup::models::file_chunk::FileChunk;
+use crate::backup::models::file_hole::FileHole;
 use crate::backup::models::file_metadata::FileMetadata;
+use crate::backup::models::lib::data_ranges;
 use crate::backup::services::chunk_storage::ChunkStorage;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use fastcdc::v2020::{ChunkData, StreamCDC};
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::iter;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 pub struct FileChunker {
     backup_config: Arc<BackupConfig>,
     chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    compression_statistics: CompressionStatistics,
 }
 
 impl FileChunker {
+    // chunks of one file waiting to be stored, each holds its data in memory
+    const MAX_PENDING_CHUNKS: usize = 64;
+
     pub fn new(
         backup_config: Arc<BackupConfig>,
         chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
@@ -22,38 +37,138 @@ impl FileChunker {
         FileChunker {
             backup_config,
             chunk_storage,
+            compression_statistics: CompressionStatistics::default(),
+        }
+    }
+
+    pub fn compression_statistics(&self) -> &CompressionStatistics {
+        &self.compression_statistics
+    }
+
+    // the extension and the magic bytes at the start of the file apply to all its chunks
+    fn file_skip_reason(&self, file_path: &Path, file: &mut File) -> Result<Option<SkipReason>> {
+        if self.backup_config.compression == Compression::None {
+            return Ok(None);
         }
+        let mut head = Vec::with_capacity(MAGIC_BYTES_SIZE);
+        file.take(MAGIC_BYTES_SIZE as u64).read_to_end(&mut head)?;
+        Ok(file_skip_reason(file_path, &head))
+    }
+
+    // only sparse files are checked for holes, so chunk boundaries of other files stay the same
+    fn data_ranges(file: &File) -> Result<Vec<Range<u64>>> {
+        let metadata = file.metadata()?;
+        let data_ranges = if metadata.blocks() * 512 < metadata.len() {
+            data_ranges(file, metadata.len())?
+        } else {
+            None
+        };
+        Ok(data_ranges.unwrap_or_else(|| iter::once(0..metadata.len()).collect()))
+    }
+
+    fn store_chunk(
+        &self,
+        chunk_data: ChunkData,
+        offset: u64,
+        file_skip_reason: Option<SkipReason>,
+    ) -> Result<FileChunk> {
+        let chunk = Chunk::new(&chunk_data, self.backup_config.chunk_id_key()?.as_ref());
+
+        // files are chunked in parallel, only the first one to add a chunk writes it,
+        // so the chunk is removed again if it could not be stored
+        if self.chunk_storage.add_chunk_if_not_exists(chunk.clone())? {
+            let skip_reason = match self.chunk_storage.store_chunk(
+                &chunk.hash,
+                &chunk_data.data,
+                file_skip_reason,
+            ) {
+                Ok(skip_reason) => skip_reason,
+                Err(err) => {
+                    self.chunk_storage.remove_chunk(&chunk.hash)?;
+                    return Err(err);
+                }
+            };
+            if self.backup_config.compression != Compression::None {
+                self.compression_statistics.record(skip_reason);
+            }
+        }
+
+        Ok(FileChunk {
+            hash: chunk.hash,
+            offset,
+            length: chunk_data.length,
+        })
     }
 
     pub fn chunk_file(&self, file_path: &Path) -> Result<FileMetadata> {
-        let file = File::open(file_path).expect("cannot open file!");
+        let mut file = File::open(file_path).map_err(|err| {
+            Error::msg(format!("Could not open {}: {}", file_path.display(), err))
+        })?;
         let mut file_metadata = FileMetadata::new(file_path.display().to_string());
-        let chunker = StreamCDC::new(
-            &file,
-            self.backup_config.min_size(),
-            self.backup_config.average_size,
-            self.backup_config.max_size(),
-        );
-
-        for chunk_data_result in chunker.into_iter() {
-            let chunk_data: ChunkData = chunk_data_result?;
-            let chunk = Chunk::from(&chunk_data);
-
-            if !self.chunk_storage.chunk_exists(&chunk.hash) {
-                self.chunk_storage
-                    .store_chunk(&chunk.hash, &chunk_data.data)
-                    .expect("cannot write chunk!");
-                self.chunk_storage.add_chunk(chunk.clone())?;
+        let length = file.metadata()?.len();
+        let file_skip_reason = self.file_skip_reason(file_path, &mut file)?;
+
+        let mut hole_start = 0;
+        for data_range in Self::data_ranges(&file)? {
+            if data_range.start > hole_start {
+                file_metadata.holes.push(FileHole {
+                    offset: hole_start,
+                    length: data_range.start - hole_start,
+                });
             }
+            hole_start = data_range.end;
 
-            file_metadata.add_chunk(
-                chunk.hash.to_string(),
-                FileChunk {
-                    hash: chunk.hash.to_string(),
-                    offset: chunk_data.offset.clone(),
-                    length: chunk_data.length.clone(),
-                },
+            // holes are never read, chunking restarts after every hole
+            file.seek(SeekFrom::Start(data_range.start))?;
+            let chunker = StreamCDC::new(
+                (&file).take(data_range.end - data_range.start),
+                self.backup_config.min_size,
+                self.backup_config.average_size,
+                self.backup_config.max_size,
             );
+
+            // chunks are hashed, compressed and stored on the pool while the file is read on,
+            // once MAX_PENDING_CHUNKS are queued the reading thread stores the next chunk itself
+            let pending_chunks = AtomicUsize::new(0);
+            let file_chunk_results = Mutex::new(Vec::new());
+            rayon::in_place_scope(|scope| -> Result<()> {
+                for chunk_data_result in chunker.into_iter() {
+                    let chunk_data: ChunkData = chunk_data_result?;
+                    let offset = data_range.start + chunk_data.offset;
+                    if pending_chunks.load(Ordering::Acquire) >= Self::MAX_PENDING_CHUNKS {
+                        let file_chunk_result =
+                            self.store_chunk(chunk_data, offset, file_skip_reason);
+                        file_chunk_results.lock().unwrap().push(file_chunk_result);
+                        continue;
+                    }
+                    pending_chunks.fetch_add(1, Ordering::AcqRel);
+                    let pending_chunks = &pending_chunks;
+                    let file_chunk_results = &file_chunk_results;
+                    scope.spawn(move |_| {
+                        let file_chunk_result =
+                            self.store_chunk(chunk_data, offset, file_skip_reason);
+                        file_chunk_results.lock().unwrap().push(file_chunk_result);
+                        pending_chunks.fetch_sub(1, Ordering::AcqRel);
+                    });
+                }
+                Ok(())
+            })?;
+
+            let mut file_chunks = file_chunk_results
+                .into_inner()
+                .unwrap()
+                .into_iter()
+                .collect::<Result<Vec<FileChunk>>>()?;
+            file_chunks.sort_by_key(|file_chunk| file_chunk.offset);
+            for file_chunk in file_chunks {
+                file_metadata.add_chunk(file_chunk);
+            }
+        }
+        if length > hole_start {
+            file_metadata.holes.push(FileHole {
+                offset: hole_start,
+                length: length - hole_start,
+            });
         }
 
         Ok(file_metadata)
@@ -0,0 +1,98 @@
+use crate::backup::models::backup_config::BackupConfig;
+use crate::backup::models::backup_metadata::BackupMetadata;
+use crate::backup::models::retention_policy::RetentionPolicy;
+use crate::backup::models::snapshot::Snapshot;
+use crate::backup::services::chunk_storage::ChunkStorage;
+use crate::backup::services::prune_service::{PruneService, PruneStatistics};
+use anyhow::{Error, Result};
+use itertools::Itertools;
+use log::info;
+use std::path::Path;
+use std::sync::Arc;
+
+pub struct ForgetService {
+    backup_config: Arc<BackupConfig>,
+    chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+}
+
+impl ForgetService {
+    pub fn new(
+        backup_config: Arc<BackupConfig>,
+        chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    ) -> ForgetService {
+        ForgetService {
+            backup_config,
+            chunk_storage,
+        }
+    }
+
+    // removes the snapshots not kept by the retention policy, which is applied
+    // separately to the snapshots of every hostname and source path
+    pub fn forget(
+        &mut self,
+        retention_policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<Vec<Snapshot>> {
+        if retention_policy.is_empty() {
+            return Err(Error::msg(
+                "No retention policy given, refusing to forget all snapshots",
+            ));
+        }
+
+        let input_path = Path::new(&self.backup_config.input_path);
+        let snapshots =
+            BackupMetadata::snapshots(input_path, self.backup_config.repository_key.as_ref())?;
+
+        let mut forgotten_snapshots = Vec::new();
+        for ((hostname, source_path), group) in &snapshots
+            .iter()
+            .sorted_by(|a, b| {
+                Ord::cmp(
+                    &(&a.hostname, &a.source_path),
+                    &(&b.hostname, &b.source_path),
+                )
+            })
+            .chunk_by(|snapshot| (snapshot.hostname.clone(), snapshot.source_path.clone()))
+        {
+            let group_snapshots: Vec<Snapshot> = group.cloned().collect();
+            let kept_snapshot_ids = retention_policy.keep(&group_snapshots);
+            info!(
+                "Keeping {} of {} snapshots of {}:{}",
+                kept_snapshot_ids.len(),
+                group_snapshots.len(),
+                hostname,
+                source_path
+            );
+
+            for snapshot in group_snapshots {
+                if kept_snapshot_ids.contains(&snapshot.id) {
+                    continue;
+                }
+
+                if dry_run {
+                    info!("Would forget snapshot {}", snapshot.short_id());
+                } else {
+                    info!("Forgetting snapshot {}", snapshot.short_id());
+                    BackupMetadata::remove(input_path, &snapshot.id)?;
+                }
+                forgotten_snapshots.push(snapshot);
+            }
+        }
+        Ok(forgotten_snapshots)
+    }
+
+    // forgets snapshots and deletes the chunks only they referenced
+    pub fn forget_and_prune(
+        &mut self,
+        retention_policy: &RetentionPolicy,
+        dry_run: bool,
+    ) -> Result<PruneStatistics> {
+        let forgotten_snapshots = self.forget(retention_policy, dry_run)?;
+
+        // a dry run keeps the snapshots, prune has to skip them itself
+        let mut prune_service =
+            PruneService::new(self.backup_config.clone(), self.chunk_storage.clone())
+                .with_forgotten_snapshots(&forgotten_snapshots);
+        prune_service.prune(dry_run)
+    }
+}
@@ -1,5 +1,11 @@
 pub mod backup_service;
+pub mod check_service;
 pub mod chunk_reader_writer;
 pub mod chunk_storage;
+pub mod dictionary_service;
 pub mod file_chunker;
+pub mod forget_service;
+pub mod path_filter;
+pub mod prune_service;
+pub mod redis_chunk_storage;
 pub mod restore_service;
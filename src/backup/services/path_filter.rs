@@ -0,0 +1,102 @@
+use crate::backup::models::backup_options::BackupOptions;
+use anyhow::{Error, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::debug;
+use std::path::Path;
+use walkdir::DirEntry;
+
+// decides which entries of a walk are backed up, excluded directories are not descended into
+pub struct PathFilter {
+    // --exclude-file, --exclude and --include patterns
+    patterns: Gitignore,
+    // (depth, matcher) of the .hoardignore files above the current entry, innermost last
+    ignore_files: Vec<(usize, Gitignore)>,
+    exclude_if_present: Vec<String>,
+}
+
+impl PathFilter {
+    pub const IGNORE_FILE: &'static str = ".hoardignore";
+
+    pub fn new(input_path: &Path, backup_options: &BackupOptions) -> Result<PathFilter> {
+        let mut builder = GitignoreBuilder::new(input_path);
+        for exclude_file in backup_options.exclude_files.iter() {
+            if let Some(err) = builder.add(exclude_file) {
+                return Err(Error::msg(format!(
+                    "Could not read exclude file {}: {}",
+                    exclude_file.display(),
+                    err
+                )));
+            }
+        }
+        for exclude in backup_options.excludes.iter() {
+            builder.add_line(None, exclude)?;
+        }
+        // the last matching pattern wins, so includes come last
+        for include in backup_options.includes.iter() {
+            builder.add_line(None, &format!("!{}", include))?;
+        }
+
+        Ok(PathFilter {
+            patterns: builder.build()?,
+            ignore_files: Vec::new(),
+            exclude_if_present: backup_options.exclude_if_present.clone(),
+        })
+    }
+
+    // has to be called for every entry in walk order, the input path itself is never excluded
+    pub fn is_excluded(&mut self, dir_entry: &DirEntry) -> Result<bool> {
+        let depth = dir_entry.depth();
+        while matches!(self.ignore_files.last(), Some((ignore_depth, _)) if *ignore_depth >= depth)
+        {
+            self.ignore_files.pop();
+        }
+
+        let path = dir_entry.path();
+        let is_dir = dir_entry.file_type().is_dir();
+        if depth > 0 && self.matches(path, is_dir) {
+            debug!("Excluding: {}", path.display());
+            return Ok(true);
+        }
+        if !is_dir {
+            return Ok(false);
+        }
+
+        if depth > 0 {
+            if let Some(marker) = self
+                .exclude_if_present
+                .iter()
+                .find(|marker| path.join(marker).exists())
+            {
+                debug!("Excluding: {}, it contains {}", path.display(), marker);
+                return Ok(true);
+            }
+        }
+
+        let ignore_file_path = path.join(Self::IGNORE_FILE);
+        if ignore_file_path.is_file() {
+            let mut builder = GitignoreBuilder::new(path);
+            if let Some(err) = builder.add(&ignore_file_path) {
+                return Err(Error::msg(format!(
+                    "Could not read {}: {}",
+                    ignore_file_path.display(),
+                    err
+                )));
+            }
+            self.ignore_files.push((depth, builder.build()?));
+        }
+        Ok(false)
+    }
+
+    // the innermost .hoardignore file with a matching pattern decides, the options come last
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        for (_, ignore_file) in self.ignore_files.iter().rev() {
+            match ignore_file.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        self.patterns.matched(path, is_dir).is_ignore()
+    }
+}
@@ -0,0 +1,154 @@
+use crate::backup::models::backup_config::BackupConfig;
+use crate::backup::models::backup_metadata::{BackupMetadata, SerializationType};
+use crate::backup::models::chunk_index::ChunkIndex;
+use crate::backup::models::snapshot::Snapshot;
+use crate::backup::services::chunk_storage::ChunkStorage;
+use anyhow::Result;
+use log::{debug, info};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Default, Clone)]
+pub struct PruneStatistics {
+    pub referenced_chunks: usize,
+    pub removed_chunks: usize,
+    pub reclaimed_bytes: u64,
+    pub repacked_packs: usize,
+}
+
+pub struct PruneService {
+    backup_config: Arc<BackupConfig>,
+    chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    // snapshots that a dry run of forget would remove, they reference no chunks
+    forgotten_snapshot_ids: HashSet<String>,
+}
+
+impl PruneService {
+    // packs with more unused space are rewritten
+    const MAX_UNUSED_RATIO: f64 = 0.2;
+
+    pub fn new(
+        backup_config: Arc<BackupConfig>,
+        chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
+    ) -> PruneService {
+        PruneService {
+            backup_config,
+            chunk_storage,
+            forgotten_snapshot_ids: HashSet::new(),
+        }
+    }
+
+    pub fn with_forgotten_snapshots(mut self, forgotten_snapshots: &[Snapshot]) -> Self {
+        self.forgotten_snapshot_ids = forgotten_snapshots
+            .iter()
+            .map(|snapshot| snapshot.id.clone())
+            .collect();
+        self
+    }
+
+    // all chunks referenced by any snapshot of the repository
+    pub fn referenced_chunks(&self) -> Result<HashSet<String>> {
+        let mut referenced_chunks = HashSet::new();
+        for backup_metadata in BackupMetadata::list(
+            Path::new(&self.backup_config.input_path),
+            self.backup_config.repository_key.as_ref(),
+        )? {
+            if self
+                .forgotten_snapshot_ids
+                .contains(&backup_metadata.snapshot.id)
+            {
+                continue;
+            }
+            for file_metadata in backup_metadata.file_metadata_map.values() {
+                for file_chunk in file_metadata.chunks.iter() {
+                    referenced_chunks.insert(file_chunk.hash.clone());
+                }
+            }
+        }
+        Ok(referenced_chunks)
+    }
+
+    // deletes every chunk that is not referenced by a snapshot,
+    // must not run while a backup writes into the same repository
+    pub fn prune(&mut self, dry_run: bool) -> Result<PruneStatistics> {
+        info!("Pruning repository: {}...", self.backup_config.input_path);
+        let start = Instant::now();
+        let input_path = Path::new(&self.backup_config.input_path);
+        let repository_key = self.backup_config.repository_key.as_ref();
+
+        let referenced_chunks = self.referenced_chunks()?;
+        if !dry_run {
+            self.chunk_storage.remove_claims()?;
+        }
+        self.chunk_storage
+            .load_chunk_map(ChunkIndex::deserialize(input_path, repository_key)?.chunk_map)?;
+
+        // chunks of interrupted backups are stored but were never added to the index
+        let mut unreferenced_chunks: HashSet<String> = self
+            .chunk_storage
+            .chunk_map()?
+            .into_keys()
+            .filter(|hash| !referenced_chunks.contains(hash))
+            .collect();
+        unreferenced_chunks.extend(
+            self.chunk_storage
+                .stored_chunks()?
+                .into_iter()
+                .filter(|hash| !referenced_chunks.contains(hash)),
+        );
+
+        let mut prune_statistics = PruneStatistics {
+            referenced_chunks: referenced_chunks.len(),
+            ..Default::default()
+        };
+
+        for hash in unreferenced_chunks.iter() {
+            // chunks that are only in the index have nothing left to reclaim
+            let stored_chunk_size = self.chunk_storage.stored_chunk_size(hash).ok();
+            // the space of packed chunks is counted when their pack is rewritten
+            let is_packed = self.chunk_storage.is_packed(hash)?;
+
+            if dry_run {
+                debug!("Would remove chunk: {}", hash);
+            } else {
+                debug!("Removing chunk: {}", hash);
+                if stored_chunk_size.is_some() {
+                    self.chunk_storage.delete_chunk(hash)?;
+                }
+                self.chunk_storage.remove_chunk(hash)?;
+            }
+
+            prune_statistics.removed_chunks += 1;
+            if !is_packed {
+                prune_statistics.reclaimed_bytes += stored_chunk_size.unwrap_or_default();
+            }
+        }
+
+        let repack_statistics =
+            self.chunk_storage
+                .repack(Self::MAX_UNUSED_RATIO, &unreferenced_chunks, dry_run)?;
+        prune_statistics.repacked_packs = repack_statistics.repacked_packs;
+        prune_statistics.reclaimed_bytes += repack_statistics.reclaimed_bytes;
+
+        if !dry_run {
+            ChunkIndex::new(self.chunk_storage.chunk_map()?).serialize(
+                input_path,
+                SerializationType::MessagePack,
+                repository_key,
+            )?;
+        }
+
+        info!(
+            "{} {} of {} chunks and repack {} packs, {} bytes reclaimed - took {:?}",
+            if dry_run { "Would remove" } else { "Removed" },
+            prune_statistics.removed_chunks,
+            prune_statistics.removed_chunks + prune_statistics.referenced_chunks,
+            prune_statistics.repacked_packs,
+            prune_statistics.reclaimed_bytes,
+            start.elapsed()
+        );
+        Ok(prune_statistics)
+    }
+}
@@ -0,0 +1,300 @@
+use crate::backup::models::backup_config::BackupConfig;
+use crate::backup::models::chunk::Chunk;
+use crate::backup::models::compressibility::SkipReason;
+use crate::backup::services::chunk_storage::{
+    ChunkMap, ChunkStorage, LocalChunkStorage, RepackStatistics,
+};
+use anyhow::{Error, Result};
+use itertools::Itertools;
+use log::info;
+use redis::{Client, Commands, Connection, RedisResult};
+use std::collections::HashSet;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// keeps the chunk index in redis or dragonfly, so hosts backing up into the same
+// repository dedup against each other, chunk data is stored like by LocalChunkStorage
+pub struct RedisChunkStorage {
+    client: Client,
+    // idle connections, workers use their own so they do not wait for each other
+    connections: Mutex<Vec<Connection>>,
+    // hash of chunk id -> serialized chunk, or CLAIM while its data is not flushed
+    key: String,
+    // set once the repository index was added to the hash
+    loaded_key: String,
+    // claimed by this process, published once their data is flushed, so other hosts never
+    // reference a chunk that is lost if this one fails before writing its pack
+    pending_chunks: Mutex<ChunkMap>,
+    // claimed by other hosts, they have to be published before this backup is written
+    claimed_chunks: Mutex<HashSet<String>>,
+    // published by this process
+    added_chunks: Mutex<ChunkMap>,
+    local_chunk_storage: LocalChunkStorage,
+}
+
+impl RedisChunkStorage {
+    const PIPELINE_SIZE: usize = 1000;
+    // placeholder of a chunk that is being stored, serialized chunks are never empty
+    const CLAIM: &'static str = "";
+    // how long to wait for other hosts to store the chunks they claimed
+    const CLAIM_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+    pub fn new(
+        backup_config: Arc<BackupConfig>,
+        repository_path: &Path,
+        redis_url: &str,
+        repository_id: &str,
+    ) -> Result<Self> {
+        if repository_id.is_empty() {
+            return Err(Error::msg(
+                "A shared chunk index requires a repository id, create the repository with init",
+            ));
+        }
+        let client = redis::Client::open(redis_url)?;
+        let connection = client
+            .get_connection()
+            .map_err(|err| Error::msg(format!("Could not connect to {}: {}", redis_url, err)))?;
+        Ok(RedisChunkStorage {
+            client,
+            connections: Mutex::new(vec![connection]),
+            key: format!("hoard:{}:chunks", repository_id),
+            loaded_key: format!("hoard:{}:loaded", repository_id),
+            pending_chunks: Default::default(),
+            claimed_chunks: Default::default(),
+            added_chunks: Default::default(),
+            local_chunk_storage: LocalChunkStorage::new(backup_config, repository_path)?,
+        })
+    }
+
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> RedisResult<T>) -> Result<T> {
+        let idle_connection = self.connections.lock().unwrap().pop();
+        let mut connection = match idle_connection {
+            Some(connection) => connection,
+            None => self.client.get_connection()?,
+        };
+        let result = f(&mut connection);
+        self.connections.lock().unwrap().push(connection);
+        Ok(result?)
+    }
+
+    // waits until the chunks other hosts claimed are stored, a snapshot must not
+    // reference chunks that are lost if the other host fails
+    fn wait_for_claimed_chunks(&self) -> Result<()> {
+        let start = Instant::now();
+        let mut claimed_chunks: Vec<String> = mem::take(&mut *self.claimed_chunks.lock().unwrap())
+            .into_iter()
+            .collect();
+        while !claimed_chunks.is_empty() {
+            let mut still_claimed_chunks = Vec::new();
+            for hashes in claimed_chunks.chunks(Self::PIPELINE_SIZE) {
+                let chunks: Vec<Option<String>> =
+                    self.with_connection(|connection| connection.hget(&self.key, hashes))?;
+                for (hash, chunk) in hashes.iter().zip(chunks) {
+                    match chunk.as_deref() {
+                        None => {
+                            return Err(Error::msg(format!(
+                                "Chunk {} was claimed by another host that could not store it, run the backup again",
+                                hash
+                            )))
+                        }
+                        Some(Self::CLAIM) => still_claimed_chunks.push(hash.clone()),
+                        Some(_) => {}
+                    }
+                }
+            }
+            claimed_chunks = still_claimed_chunks;
+            if claimed_chunks.is_empty() {
+                break;
+            }
+            if start.elapsed() > Self::CLAIM_TIMEOUT {
+                return Err(Error::msg(format!(
+                    "{} chunks are still claimed by other hosts after {:?}, prune drops the claims of failed backups",
+                    claimed_chunks.len(),
+                    Self::CLAIM_TIMEOUT
+                )));
+            }
+            info!(
+                "Waiting for other hosts to store {} chunks...",
+                claimed_chunks.len()
+            );
+            thread::sleep(Duration::from_secs(1));
+        }
+        Ok(())
+    }
+}
+
+impl ChunkStorage for RedisChunkStorage {
+    fn add_chunk(&self, chunk: Chunk) -> Result<()> {
+        self.pending_chunks
+            .lock()
+            .unwrap()
+            .insert(chunk.hash.clone(), chunk);
+        Ok(())
+    }
+
+    fn chunk_exists(&self, hash: &str) -> Result<bool> {
+        if self.pending_chunks.lock().unwrap().contains_key(hash) {
+            return Ok(true);
+        }
+        self.with_connection(|connection| connection.hexists(&self.key, hash))
+    }
+
+    // HSETNX claims the chunk, so only one host stores it
+    fn add_chunk_if_not_exists(&self, chunk: Chunk) -> Result<bool> {
+        if self
+            .pending_chunks
+            .lock()
+            .unwrap()
+            .contains_key(&chunk.hash)
+        {
+            return Ok(false);
+        }
+        let (claimed, value): (bool, Option<String>) = self.with_connection(|connection| {
+            redis::pipe()
+                .hset_nx(&self.key, &chunk.hash, Self::CLAIM)
+                .hget(&self.key, &chunk.hash)
+                .query(connection)
+        })?;
+        if claimed {
+            self.pending_chunks
+                .lock()
+                .unwrap()
+                .insert(chunk.hash.clone(), chunk);
+        } else if value.as_deref() == Some(Self::CLAIM)
+            && !self
+                .pending_chunks
+                .lock()
+                .unwrap()
+                .contains_key(&chunk.hash)
+        {
+            self.claimed_chunks.lock().unwrap().insert(chunk.hash);
+        }
+        Ok(claimed)
+    }
+
+    // the whole shared index, only prune needs it
+    fn chunk_map(&self) -> Result<ChunkMap> {
+        let mut chunk_map = ChunkMap::new();
+        let chunks: Vec<(String, String)> = self.with_connection(|connection| {
+            Ok(connection
+                .hscan::<_, (String, String)>(&self.key)?
+                .collect())
+        })?;
+        for (hash, chunk) in chunks {
+            if chunk != Self::CLAIM {
+                chunk_map.insert(hash, serde_json::from_str(&chunk)?);
+            }
+        }
+        chunk_map.extend(self.pending_chunks.lock().unwrap().clone());
+        Ok(chunk_map)
+    }
+
+    // the repository index is added to the shared index once, existing entries are kept
+    fn load_chunk_map(&self, chunk_map: ChunkMap) -> Result<()> {
+        if self.has_chunk_map()? {
+            return Ok(());
+        }
+        for chunks in &chunk_map.into_values().chunks(Self::PIPELINE_SIZE) {
+            let mut pipeline = redis::pipe();
+            for chunk in chunks {
+                pipeline
+                    .hset_nx(&self.key, &chunk.hash, serde_json::to_string(&chunk)?)
+                    .ignore();
+            }
+            self.with_connection(|connection| pipeline.query::<()>(connection))?;
+        }
+        self.with_connection(|connection| connection.set(&self.loaded_key, 1))
+    }
+
+    fn has_chunk_map(&self) -> Result<bool> {
+        self.with_connection(|connection| connection.exists(&self.loaded_key))
+    }
+
+    fn added_chunks(&self) -> Result<ChunkMap> {
+        let mut added_chunks = self.added_chunks.lock().unwrap().clone();
+        added_chunks.extend(self.pending_chunks.lock().unwrap().clone());
+        Ok(added_chunks)
+    }
+
+    fn store_chunk(
+        &self,
+        hash: &str,
+        data: &[u8],
+        skip_reason: Option<SkipReason>,
+    ) -> Result<Option<SkipReason>> {
+        self.local_chunk_storage
+            .store_chunk(hash, data, skip_reason)
+    }
+
+    fn load_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        self.local_chunk_storage.load_chunk(hash)
+    }
+
+    // also drops the claim of a chunk that could not be stored
+    fn remove_chunk(&self, hash: &str) -> Result<()> {
+        self.pending_chunks.lock().unwrap().remove(hash);
+        self.added_chunks.lock().unwrap().remove(hash);
+        self.with_connection(|connection| connection.hdel(&self.key, hash))
+    }
+
+    fn remove_claims(&self) -> Result<()> {
+        let claimed_hashes: Vec<String> = self.with_connection(|connection| {
+            Ok(connection
+                .hscan::<_, (String, String)>(&self.key)?
+                .filter(|(_, chunk)| chunk == Self::CLAIM)
+                .map(|(hash, _)| hash)
+                .collect())
+        })?;
+        for hashes in claimed_hashes.chunks(Self::PIPELINE_SIZE) {
+            info!("Removing {} claims of failed backups", hashes.len());
+            self.with_connection(|connection| connection.hdel::<_, _, ()>(&self.key, hashes))?;
+        }
+        Ok(())
+    }
+
+    fn delete_chunk(&self, hash: &str) -> Result<()> {
+        self.local_chunk_storage.delete_chunk(hash)
+    }
+
+    fn is_packed(&self, hash: &str) -> Result<bool> {
+        self.local_chunk_storage.is_packed(hash)
+    }
+
+    fn stored_chunk_size(&self, hash: &str) -> Result<u64> {
+        self.local_chunk_storage.stored_chunk_size(hash)
+    }
+
+    fn stored_chunks(&self) -> Result<Vec<String>> {
+        self.local_chunk_storage.stored_chunks()
+    }
+
+    // the pending chunks replace their claims after their packs are written
+    fn flush(&self) -> Result<()> {
+        self.local_chunk_storage.flush()?;
+        let pending_chunks = mem::take(&mut *self.pending_chunks.lock().unwrap());
+        for chunks in &pending_chunks.values().chunks(Self::PIPELINE_SIZE) {
+            let mut pipeline = redis::pipe();
+            for chunk in chunks {
+                pipeline
+                    .hset(&self.key, &chunk.hash, serde_json::to_string(&chunk)?)
+                    .ignore();
+            }
+            self.with_connection(|connection| pipeline.query::<()>(connection))?;
+        }
+        self.added_chunks.lock().unwrap().extend(pending_chunks);
+        self.wait_for_claimed_chunks()
+    }
+
+    fn repack(
+        &self,
+        max_unused_ratio: f64,
+        removed_chunks: &HashSet<String>,
+        dry_run: bool,
+    ) -> Result<RepackStatistics> {
+        self.local_chunk_storage
+            .repack(max_unused_ratio, removed_chunks, dry_run)
+    }
+}
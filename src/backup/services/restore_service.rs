@@ -1,64 +1,281 @@
 use crate::backup::models::backup_config::BackupConfig;
 use crate::backup::models::backup_metadata::BackupMetadata;
-use crate::backup::services::chunk_reader_writer::ChunkReaderWriter;
+use crate::backup::models::chunk_index::ChunkIndex;
+use crate::backup::models::extended_attributes::ExtendedAttributesMap;
+use crate::backup::models::file_attributes::FileAttributes;
+use crate::backup::models::hardlink_group::HardlinkGroup;
+use crate::backup::models::lib::{
+    group_id, make_node, normalize_relative_path, set_file_times, user_id,
+};
+use crate::backup::models::restore_options::RestoreOptions;
+use crate::backup::models::special_file::SpecialFile;
+use crate::backup::models::symlink::Symlink;
 use crate::backup::services::chunk_storage::ChunkStorage;
+use anyhow::{Error, Result};
 use itertools::Itertools;
-use log::debug;
-use std::path::{Path, PathBuf};
+use log::{debug, info, warn};
+use std::cmp::Reverse;
+use std::fs::{self, File, Permissions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::os::unix;
+use std::os::unix::fs::{lchown, PermissionsExt};
+use std::path::{self, Path, PathBuf};
 use std::sync::Arc;
 
 pub struct RestoreService {
     backup_config: Arc<BackupConfig>,
 
     chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
-    chunk_reader_writer: Arc<ChunkReaderWriter>,
+    restore_options: RestoreOptions,
+    // of the snapshot being restored
+    extended_attributes: ExtendedAttributesMap,
 }
 
 impl RestoreService {
     pub fn new(
         backup_config: Arc<BackupConfig>,
         chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>>,
-        chunk_reader_writer: Arc<ChunkReaderWriter>,
+        restore_options: RestoreOptions,
     ) -> RestoreService {
         RestoreService {
             backup_config,
             chunk_storage,
-            chunk_reader_writer,
+            restore_options,
+            extended_attributes: Default::default(),
         }
     }
 
-    pub fn restore(&mut self) -> anyhow::Result<()> {
-        let backup_metadata =
-            BackupMetadata::deserialize(Path::new(&self.backup_config.input_path))?;
+    // backed up paths are restored below the output path, snapshots may have been
+    // tampered with, so paths leading outside of it are rejected
+    fn restore_path(&self, backup_path: &str) -> Result<PathBuf> {
+        Ok(PathBuf::from(&self.backup_config.output_path)
+            .join(normalize_relative_path(Path::new(backup_path))?))
+    }
+
+    // restores the given snapshot, or the latest one if none is given
+    pub fn restore(&mut self, snapshot_id: Option<&str>) -> Result<()> {
+        let input_path = Path::new(&self.backup_config.input_path);
+        let repository_key = self.backup_config.repository_key.as_ref();
+        let backup_metadata = match snapshot_id {
+            Some(snapshot_id) => BackupMetadata::find(input_path, snapshot_id, repository_key)?,
+            None => BackupMetadata::latest(input_path, repository_key)?.ok_or_else(|| {
+                Error::msg(format!(
+                    "No snapshots found in {}",
+                    self.backup_config.input_path
+                ))
+            })?,
+        };
+        info!(
+            "Restoring snapshot {} of {} from {}",
+            backup_metadata.snapshot.short_id(),
+            backup_metadata.snapshot.source_path,
+            backup_metadata.snapshot.time
+        );
+
         let file_metadata_map = backup_metadata.file_metadata_map.clone();
+        self.extended_attributes = backup_metadata.extended_attributes.clone();
         self.chunk_storage
-            .load_chunk_map(backup_metadata.chunk_map.clone())?;
+            .load_chunk_map(ChunkIndex::deserialize(input_path, repository_key)?.chunk_map)?;
 
-        for (output_file_path, file_metadata) in file_metadata_map.iter() {
-            let output_filepath = output_file_path
-                .strip_prefix("/")
-                .unwrap_or(output_file_path);
+        // empty directories are created as well
+        for directory in backup_metadata.directories.iter() {
+            fs::create_dir_all(self.restore_path(&directory.path)?)?;
+        }
 
-            let moved_output_filepath =
-                PathBuf::from(&self.backup_config.output_path).join(output_filepath);
+        for (output_file_path, file_metadata) in file_metadata_map.iter() {
+            let moved_output_filepath = self.restore_path(output_file_path)?;
 
             debug!("Restoring: {}", moved_output_filepath.display());
 
-            let mut writer = self
-                .chunk_reader_writer
-                .build_operator()?
-                .writer(moved_output_filepath.to_str().unwrap())?;
+            if let Some(parent_path) = moved_output_filepath.parent() {
+                fs::create_dir_all(parent_path)?;
+            }
+            // files linked by an earlier restore must not be written through
+            Self::remove_existing(&moved_output_filepath)?;
+            let mut writer = BufWriter::new(File::create(&moved_output_filepath)?);
+
+            // holes are skipped, unwritten ranges of a new file do not allocate space
+            let mut position = 0;
+            for file_chunk in file_metadata.chunks.iter() {
+                if file_chunk.offset != position {
+                    writer.seek(SeekFrom::Start(file_chunk.offset))?;
+                }
+                let chunk_data = self.chunk_storage.load_chunk(&file_chunk.hash)?;
+                writer.write_all(&chunk_data)?;
+                position = file_chunk.offset + chunk_data.len() as u64;
+            }
+            let file = writer.into_inner()?;
+            if !file_metadata.holes.is_empty() {
+                file.set_len(file_metadata.size())?;
+            }
+            file.sync_all()?;
 
-            for (hash, _) in file_metadata
-                .chunks
-                .iter()
-                .sorted_by(|(_, a), (_, b)| Ord::cmp(&a.offset, &b.offset))
-            {
-                let chunk_data = self.chunk_storage.load_chunk(hash)?;
-                writer.write(chunk_data)?
+            if let Some(attributes) = &file_metadata.attributes {
+                self.apply_attributes(&moved_output_filepath, attributes)?;
+            }
+        }
+
+        for hardlink_group in backup_metadata.hardlink_groups.iter() {
+            self.restore_hardlinks(hardlink_group)?;
+        }
+
+        for special_file in backup_metadata.special_files.iter() {
+            self.restore_special_file(special_file)?;
+        }
+
+        for symlink in backup_metadata.symlinks.iter() {
+            self.restore_symlink(symlink)?;
+        }
+
+        // deepest first, writing contents would change the modification time of
+        // a directory and read only directories could not be written anymore
+        for directory in backup_metadata
+            .directories
+            .iter()
+            .sorted_by_key(|directory| Reverse(directory.depth()))
+        {
+            if let Some(attributes) = &directory.attributes {
+                self.apply_attributes(&self.restore_path(&directory.path)?, attributes)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_hardlinks(&self, hardlink_group: &HardlinkGroup) -> Result<()> {
+        let original_path = self.restore_path(&hardlink_group.path)?;
+        for link in hardlink_group.links.iter() {
+            let link_path = self.restore_path(link)?;
+            debug!(
+                "Restoring hardlink: {} -> {}",
+                link_path.display(),
+                original_path.display()
+            );
+
+            if let Some(parent_path) = link_path.parent() {
+                fs::create_dir_all(parent_path)?;
+            }
+            Self::remove_existing(&link_path)?;
+            fs::hard_link(&original_path, &link_path).map_err(|err| {
+                Error::msg(format!(
+                    "Could not create hardlink {}: {}",
+                    link_path.display(),
+                    err
+                ))
+            })?;
+        }
+        Ok(())
+    }
+
+    // an existing link or file is replaced, like restored files are overwritten
+    fn remove_existing(path: &Path) -> Result<()> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.is_dir() {
+                fs::remove_file(path)?;
             }
-            writer.close()?;
         }
         Ok(())
     }
+
+    // device nodes can only be created by root, they are skipped otherwise
+    fn restore_special_file(&self, special_file: &SpecialFile) -> Result<()> {
+        let path = self.restore_path(&special_file.path)?;
+        debug!("Restoring {:?}: {}", special_file.file_type, path.display());
+
+        if let Some(parent_path) = path.parent() {
+            fs::create_dir_all(parent_path)?;
+        }
+        Self::remove_existing(&path)?;
+        if let Err(err) = make_node(
+            &path,
+            special_file.file_type.mode() | 0o600,
+            special_file.major,
+            special_file.minor,
+        ) {
+            warn!("Could not create {}: {}", path.display(), err);
+            return Ok(());
+        }
+
+        if let Some(attributes) = &special_file.attributes {
+            self.apply_attributes(&path, attributes)?;
+        }
+        Ok(())
+    }
+
+    // links are created as they were, dangling links included
+    fn restore_symlink(&self, symlink: &Symlink) -> Result<()> {
+        let link_path = self.restore_path(&symlink.from)?;
+        let target_path = if self.restore_options.rewrite_absolute_symlinks
+            && Path::new(&symlink.to).is_absolute()
+        {
+            path::absolute(self.restore_path(&symlink.to)?)?
+        } else {
+            PathBuf::from(&symlink.to)
+        };
+        debug!(
+            "Restoring symlink: {} -> {}",
+            link_path.display(),
+            target_path.display()
+        );
+
+        if let Some(parent_path) = link_path.parent() {
+            fs::create_dir_all(parent_path)?;
+        }
+        Self::remove_existing(&link_path)?;
+        unix::fs::symlink(&target_path, &link_path).map_err(|err| {
+            Error::msg(format!(
+                "Could not create symlink {}: {}",
+                link_path.display(),
+                err
+            ))
+        })?;
+
+        if let Some(attributes) = &symlink.attributes {
+            self.apply_attributes(&link_path, attributes)?;
+        }
+        Ok(())
+    }
+
+    // ownership has to be changed first, chown clears the setuid and setgid bits
+    fn apply_attributes(&self, path: &Path, attributes: &FileAttributes) -> Result<()> {
+        if self.restore_options.restore_ownership {
+            // names are preferred, ids differ between systems
+            let uid = attributes
+                .user
+                .as_deref()
+                .and_then(user_id)
+                .unwrap_or(attributes.uid);
+            let gid = attributes
+                .group
+                .as_deref()
+                .and_then(group_id)
+                .unwrap_or(attributes.gid);
+
+            lchown(path, Some(uid), Some(gid)).map_err(|err| {
+                Error::msg(format!(
+                    "Could not change owner of {} to {}:{}: {}, use --skip-ownership to restore without ownership",
+                    path.display(),
+                    uid,
+                    gid,
+                    err
+                ))
+            })?;
+        }
+
+        // link permissions can not be changed on linux
+        if !path.is_symlink() {
+            fs::set_permissions(path, Permissions::from_mode(attributes.permissions()))?;
+        }
+
+        // after chown, which drops security.capability, and after chmod, which would change the ACL mask
+        if self.restore_options.restore_extended_attributes {
+            if let Some(extended_attributes) = attributes
+                .extended_attributes_id
+                .as_ref()
+                .and_then(|id| self.extended_attributes.get(id))
+            {
+                extended_attributes.apply(path);
+            }
+        }
+        set_file_times(path, attributes.atime, attributes.mtime)
+    }
 }
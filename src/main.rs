@@ -1,30 +1,69 @@
 extern crate num_cpus;
 pub mod backup;
 
-use anyhow::Result;
+use anyhow::{Error, Result};
+use clap::builder::RangedU64ValueParser;
 use clap::{Parser, Subcommand};
 use core::str;
 use hoard_chunker::backup::models::backup_config::BackupConfig;
+use hoard_chunker::backup::models::backup_metadata::BackupMetadata;
+use hoard_chunker::backup::models::backup_options::BackupOptions;
+use hoard_chunker::backup::models::chunker_parameters::ChunkerParameters;
+use hoard_chunker::backup::models::compression::Compression;
+use hoard_chunker::backup::models::key_file::KeyFile;
+use hoard_chunker::backup::models::lib::normalize_relative_path;
+use hoard_chunker::backup::models::repository_config::{ChunkIdScheme, RepositoryConfig};
+use hoard_chunker::backup::models::repository_key::RepositoryKey;
+use hoard_chunker::backup::models::restore_options::RestoreOptions;
+use hoard_chunker::backup::models::retention_policy::RetentionPolicy;
+use hoard_chunker::backup::models::storage_url::StorageUrl;
+use hoard_chunker::backup::models::zstd_dictionary::ZstdDictionary;
 use hoard_chunker::backup::services::backup_service::BackupService;
+use hoard_chunker::backup::services::check_service::CheckService;
 use hoard_chunker::backup::services::chunk_reader_writer::ChunkReaderWriter;
 use hoard_chunker::backup::services::chunk_storage::{ChunkStorage, LocalChunkStorage};
+use hoard_chunker::backup::services::dictionary_service::DictionaryService;
 use hoard_chunker::backup::services::file_chunker::FileChunker;
+use hoard_chunker::backup::services::forget_service::ForgetService;
+use hoard_chunker::backup::services::prune_service::PruneService;
+use hoard_chunker::backup::services::redis_chunk_storage::RedisChunkStorage;
 use hoard_chunker::backup::services::restore_service::RestoreService;
 use hoard_chunker::DEFAULT_AVERAGE_SIZE;
 use log::LevelFilter;
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{self, Component, Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    #[arg(short, long)]
+    /// Average chunk size in bytes, defaults to the size the repository was created with
+    #[arg(short, long, visible_alias = "avg-size")]
     average_size: Option<u32>,
 
+    /// Minimum chunk size in bytes, defaults to a quarter of the average size
+    #[arg(long)]
+    min_size: Option<u32>,
+
+    /// Maximum chunk size in bytes, defaults to four times the average size
+    #[arg(long)]
+    max_size: Option<u32>,
+
     #[arg(short, long)]
     log_level: Option<LevelFilter>,
 
+    /// File containing the password of an encrypted repository,
+    /// falls back to HOARD_PASSWORD and then to a prompt
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// Keep the chunk index in redis or dragonfly, e.g. redis://localhost:6379,
+    /// hosts backing up into the same repository dedup against each other
+    #[arg(long)]
+    redis_url: Option<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -37,6 +76,35 @@ enum Commands {
 
         #[arg(short, long)]
         output_path: PathBuf,
+
+        /// Exclude paths matching a gitignore style pattern, can be repeated
+        #[arg(long)]
+        exclude: Vec<String>,
+
+        /// Include paths excluded by another pattern, can be repeated
+        #[arg(long)]
+        include: Vec<String>,
+
+        /// Read exclude patterns from a file, one per line, can be repeated
+        #[arg(long)]
+        exclude_file: Vec<PathBuf>,
+
+        /// Exclude directories containing a file with this name, e.g. CACHEDIR.TAG, can be repeated
+        #[arg(long)]
+        exclude_if_present: Vec<String>,
+
+        /// Read all files, also those that did not change since the last backup
+        #[arg(long)]
+        force_rehash: bool,
+
+        /// Number of threads chunking files, defaults to the number of cpus
+        #[arg(short, long, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        workers: Option<usize>,
+
+        /// Compress new chunks with none, lz4, zstd[:level] or brotli[:level] instead of
+        /// the compression of the repository
+        #[arg(long)]
+        compression: Option<Compression>,
     },
     Restore {
         #[arg(short, long)]
@@ -44,12 +112,292 @@ enum Commands {
 
         #[arg(short, long)]
         output_path: PathBuf,
+
+        /// Snapshot id (or unambiguous prefix) to restore, defaults to the latest snapshot
+        #[arg(short, long)]
+        snapshot: Option<String>,
+
+        /// Do not change the owner of restored files, required when not running as root
+        #[arg(long)]
+        skip_ownership: bool,
+
+        /// Point absolute symlink targets into the output path instead of their original location
+        #[arg(long)]
+        rewrite_absolute_symlinks: bool,
+
+        /// Do not restore extended attributes and ACLs
+        #[arg(long)]
+        skip_extended_attributes: bool,
+    },
+    Snapshots {
+        #[arg(short, long)]
+        input_path: PathBuf,
+    },
+    /// Create a repository, all other commands require one
+    Init {
+        repository_path: PathBuf,
+
+        /// Create a password protected key, chunks and metadata are encrypted
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Store chunks in fs:///path, s3://bucket/prefix, sftp://user@host/path or memory://
+        #[arg(long)]
+        storage: Option<StorageUrl>,
+
+        /// Compress chunks with none, lz4, zstd[:level] or brotli[:level], defaults to zstd:1
+        #[arg(long)]
+        compression: Option<Compression>,
+    },
+    /// Delete chunks that are not referenced by any snapshot
+    Prune {
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Only report what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Verify that all snapshots can be restored
+    Check {
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Read and verify all chunks
+        #[arg(long)]
+        read_data: bool,
+
+        /// Read and verify a random subset of the chunks, e.g. "10%"
+        #[arg(long, value_parser = parse_percentage, conflicts_with = "read_data")]
+        read_data_subset: Option<f64>,
+    },
+    /// Train a zstd dictionary from the stored chunks, later backups compress with it
+    TrainDictionary {
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Number of chunks to train on
+        #[arg(long, default_value_t = 1000)]
+        samples: usize,
+
+        /// Maximum size of the dictionary in bytes
+        #[arg(long, default_value_t = 112640)]
+        size: usize,
+
+        /// Compress later backups without a dictionary, trained dictionaries are kept
+        #[arg(long, conflicts_with_all = ["samples", "size"])]
+        disable: bool,
+    },
+    /// Forget snapshots according to a retention policy
+    Forget {
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Keep the last n snapshots
+        #[arg(long)]
+        keep_last: Option<usize>,
+
+        /// Keep the last snapshot of the last n hours
+        #[arg(long)]
+        keep_hourly: Option<usize>,
+
+        /// Keep the last snapshot of the last n days
+        #[arg(long)]
+        keep_daily: Option<usize>,
+
+        /// Keep the last snapshot of the last n weeks
+        #[arg(long)]
+        keep_weekly: Option<usize>,
+
+        /// Keep the last snapshot of the last n months
+        #[arg(long)]
+        keep_monthly: Option<usize>,
+
+        /// Keep the last snapshot of the last n years
+        #[arg(long)]
+        keep_yearly: Option<usize>,
+
+        /// Keep all snapshots within this duration of the latest snapshot, e.g. "30days"
+        #[arg(long, value_parser = humantime::parse_duration)]
+        keep_within: Option<Duration>,
+
+        /// Delete chunks that are no longer referenced afterwards
+        #[arg(long)]
+        prune: bool,
+
+        /// Only report which snapshots would be forgotten
+        #[arg(long)]
+        dry_run: bool,
     },
 }
 
+fn parse_percentage(value: &str) -> Result<f64, String> {
+    let percentage: f64 = value
+        .trim_end_matches('%')
+        .parse()
+        .map_err(|_| format!("{} is not a percentage", value))?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(format!("{} is not between 0% and 100%", value));
+    }
+    Ok(percentage)
+}
+
+fn read_password(password_file: &Option<PathBuf>, confirm: bool) -> Result<String> {
+    if let Some(password_file) = password_file {
+        return Ok(fs::read_to_string(password_file)?
+            .trim_end_matches(['\r', '\n'])
+            .to_string());
+    }
+    if let Ok(password) = std::env::var("HOARD_PASSWORD") {
+        return Ok(password);
+    }
+
+    let password = rpassword::prompt_password("Password: ")?;
+    if confirm && rpassword::prompt_password("Repeat password: ")? != password {
+        return Err(Error::msg("Passwords do not match"));
+    }
+    Ok(password)
+}
+
+// repositories with a key file are encrypted, all others are not
+fn open_repository_key(
+    repository_path: &Path,
+    password_file: &Option<PathBuf>,
+) -> Result<Option<RepositoryKey>> {
+    if !KeyFile::exists(repository_path) {
+        return Ok(None);
+    }
+    let password = read_password(password_file, false)?;
+    Ok(Some(KeyFile::open(repository_path, &password)?))
+}
+
+// sizes given on the command line win over the ones stored in the repository
+fn chunker_parameters(cli: &Cli, repository_config: &RepositoryConfig) -> ChunkerParameters {
+    let mut chunker_parameters = match (cli.average_size, repository_config.chunker_parameters) {
+        (Some(average_size), _) => ChunkerParameters::new(average_size),
+        (None, Some(chunker_parameters)) => chunker_parameters,
+        (None, None) => ChunkerParameters::new(DEFAULT_AVERAGE_SIZE),
+    };
+    if let Some(min_size) = cli.min_size {
+        chunker_parameters.min_size = min_size;
+    }
+    if let Some(max_size) = cli.max_size {
+        chunker_parameters.max_size = max_size;
+    }
+    chunker_parameters
+}
+
+// repositories created before init existed can be initialized in place, but only without encryption
+fn init_repository(
+    cli: &Cli,
+    repository_path: &Path,
+    encrypt: bool,
+    storage_url: Option<StorageUrl>,
+    compression: Compression,
+) -> Result<()> {
+    if RepositoryConfig::path(repository_path).exists() {
+        return Err(Error::msg(format!(
+            "Repository {} already exists",
+            repository_path.display()
+        )));
+    }
+    let is_empty = !repository_path.exists() || repository_path.read_dir()?.next().is_none();
+    if encrypt && !is_empty {
+        return Err(Error::msg(
+            "Repository is not empty, encryption can only be enabled for new repositories",
+        ));
+    }
+
+    // the chunk sizes of existing snapshots are not known, a guess would stop deduplication
+    if !is_empty && cli.average_size.is_none() {
+        return Err(Error::msg(format!(
+            "Repository {} already contains data, pass the --average-size its backups were chunked with",
+            repository_path.display()
+        )));
+    }
+    let chunker_parameters = chunker_parameters(cli, &RepositoryConfig::default());
+    chunker_parameters.validate()?;
+    if let Some(storage_url) = &storage_url {
+        if !storage_url.is_persistent() {
+            return Err(Error::msg(format!(
+                "{} is lost when the process exits and cannot store a repository",
+                storage_url
+            )));
+        }
+        ChunkReaderWriter::build_operator(storage_url)?;
+    }
+
+    let chunk_id_scheme = if encrypt {
+        let password = read_password(&cli.password_file, true)?;
+        KeyFile::create(repository_path, &password, &RepositoryKey::generate())?;
+        log::info!("Created key file in {}", repository_path.display());
+        ChunkIdScheme::KeyedBlake3
+    } else {
+        ChunkIdScheme::Blake3
+    };
+    let repository_config = RepositoryConfig {
+        storage_url,
+        compression,
+        ..RepositoryConfig::new(chunk_id_scheme, chunker_parameters)
+    };
+    repository_config.serialize(repository_path)?;
+    log::info!(
+        "Created repository {} in {}, chunking with {}",
+        &repository_config.id[..8],
+        repository_path.display(),
+        chunker_parameters
+    );
+    Ok(())
+}
+
+fn build_backup_config(
+    cli: &Cli,
+    input_path: &Path,
+    output_path: &Path,
+    repository_path: &Path,
+) -> Result<BackupConfig> {
+    let repository_config = RepositoryConfig::open(repository_path)?;
+    let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, input_path, output_path);
+    backup_config.set_chunker_parameters(chunker_parameters(cli, &repository_config));
+    backup_config.repository_key = open_repository_key(repository_path, &cli.password_file)?;
+    backup_config.chunk_id_scheme = repository_config.chunk_id_scheme;
+    backup_config.storage_url = repository_config.storage_url;
+    backup_config.compression = repository_config.compression;
+    backup_config.dictionaries =
+        ZstdDictionary::list(repository_path, backup_config.repository_key.as_ref())?;
+    backup_config.dictionary_id = repository_config.dictionary_id;
+
+    if backup_config.chunk_id_scheme == ChunkIdScheme::KeyedBlake3
+        && backup_config.repository_key.is_none()
+    {
+        return Err(Error::msg(
+            "Repository uses keyed chunk ids, but has no key file",
+        ));
+    }
+    Ok(backup_config)
+}
+
+fn build_chunk_storage(
+    cli: &Cli,
+    backup_config: &Arc<BackupConfig>,
+    repository_path: &Path,
+) -> Result<Arc<Box<dyn ChunkStorage + Send + Sync>>> {
+    Ok(match &cli.redis_url {
+        Some(redis_url) => Arc::new(Box::new(RedisChunkStorage::new(
+            backup_config.clone(),
+            repository_path,
+            redis_url,
+            &RepositoryConfig::open(repository_path)?.id,
+        )?)),
+        None => Arc::new(Box::new(LocalChunkStorage::new(
+            backup_config.clone(),
+            repository_path,
+        )?)),
+    })
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let average_size = cli.average_size.unwrap_or(DEFAULT_AVERAGE_SIZE);
     let log_level = cli.log_level.unwrap_or(LevelFilter::Info);
 
     CombinedLogger::init(vec![TermLogger::new(
@@ -61,16 +409,34 @@ fn main() -> Result<()> {
 
     log::set_max_level(LevelFilter::Debug);
 
-    let chunk_reader_writer = Arc::new(ChunkReaderWriter::new());
-
     match &cli.command {
         Some(Commands::Backup {
             input_path,
             output_path,
+            exclude,
+            include,
+            exclude_file,
+            exclude_if_present,
+            force_rehash,
+            workers,
+            compression,
         }) => {
-            let backup_config = Arc::new(BackupConfig::new(average_size, input_path, output_path));
-            let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> =
-                Arc::new(Box::new(LocalChunkStorage::new(backup_config.clone())));
+            // restore rejects paths with .., so they are backed up by their absolute path
+            let input_path = if input_path
+                .components()
+                .any(|component| component == Component::ParentDir)
+            {
+                Path::new("/").join(normalize_relative_path(&path::absolute(input_path)?)?)
+            } else {
+                input_path.clone()
+            };
+            let mut backup_config =
+                build_backup_config(&cli, &input_path, output_path, output_path)?;
+            if let Some(compression) = compression {
+                backup_config.compression = *compression;
+            }
+            let backup_config = Arc::new(backup_config);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, output_path)?;
             let file_chunker = Arc::new(FileChunker::new(
                 backup_config.clone(),
                 chunk_storage.clone(),
@@ -79,23 +445,152 @@ fn main() -> Result<()> {
                 backup_config.clone(),
                 file_chunker.clone(),
                 chunk_storage.clone(),
+                BackupOptions {
+                    excludes: exclude.clone(),
+                    includes: include.clone(),
+                    exclude_files: exclude_file.clone(),
+                    exclude_if_present: exclude_if_present.clone(),
+                    force_rehash: *force_rehash,
+                    workers: *workers,
+                },
             );
             backup_service.backup()?;
         }
         Some(Commands::Restore {
             input_path,
             output_path,
+            snapshot,
+            skip_ownership,
+            rewrite_absolute_symlinks,
+            skip_extended_attributes,
         }) => {
-            let backup_config = Arc::new(BackupConfig::new(average_size, input_path, output_path));
-            let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> =
-                Arc::new(Box::new(LocalChunkStorage::new(backup_config.clone())));
+            let backup_config = Arc::new(build_backup_config(
+                &cli,
+                input_path,
+                output_path,
+                input_path,
+            )?);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, input_path)?;
 
             let mut restore_service = RestoreService::new(
                 backup_config.clone(),
                 chunk_storage.clone(),
-                chunk_reader_writer.clone(),
+                RestoreOptions {
+                    restore_ownership: !skip_ownership,
+                    rewrite_absolute_symlinks: *rewrite_absolute_symlinks,
+                    restore_extended_attributes: !skip_extended_attributes,
+                },
             );
-            restore_service.restore()?;
+            restore_service.restore(snapshot.as_deref())?;
+        }
+        Some(Commands::Snapshots { input_path }) => {
+            RepositoryConfig::open(input_path)?;
+            let repository_key = open_repository_key(input_path, &cli.password_file)?;
+            for snapshot in BackupMetadata::snapshots(input_path, repository_key.as_ref())? {
+                println!(
+                    "{}  {}  {}  {}",
+                    snapshot.short_id(),
+                    snapshot.time.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.hostname,
+                    snapshot.source_path
+                );
+            }
+        }
+        Some(Commands::Init {
+            repository_path,
+            encrypt,
+            storage,
+            compression,
+        }) => init_repository(
+            &cli,
+            repository_path,
+            *encrypt,
+            storage.clone(),
+            compression.unwrap_or_default(),
+        )?,
+        Some(Commands::Prune {
+            input_path,
+            dry_run,
+        }) => {
+            let backup_config = Arc::new(build_backup_config(
+                &cli, input_path, input_path, input_path,
+            )?);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, input_path)?;
+
+            let mut prune_service = PruneService::new(backup_config.clone(), chunk_storage.clone());
+            prune_service.prune(*dry_run)?;
+        }
+        Some(Commands::Check {
+            input_path,
+            read_data,
+            read_data_subset,
+        }) => {
+            let backup_config = Arc::new(build_backup_config(
+                &cli, input_path, input_path, input_path,
+            )?);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, input_path)?;
+            let read_data_percentage = if *read_data {
+                Some(100.0)
+            } else {
+                *read_data_subset
+            };
+
+            let mut check_service = CheckService::new(backup_config.clone(), chunk_storage.clone());
+            if !check_service.check(read_data_percentage)?.is_ok() {
+                return Err(Error::msg("Repository check found errors"));
+            }
+        }
+        Some(Commands::TrainDictionary {
+            input_path,
+            samples,
+            size,
+            disable,
+        }) => {
+            let backup_config = Arc::new(build_backup_config(
+                &cli, input_path, input_path, input_path,
+            )?);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, input_path)?;
+
+            let dictionary_service = DictionaryService::new(backup_config, chunk_storage);
+            if *disable {
+                dictionary_service.disable()?;
+            } else {
+                dictionary_service.train(*samples, *size)?;
+            }
+        }
+        Some(Commands::Forget {
+            input_path,
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_yearly,
+            keep_within,
+            prune,
+            dry_run,
+        }) => {
+            let backup_config = Arc::new(build_backup_config(
+                &cli, input_path, input_path, input_path,
+            )?);
+            let chunk_storage = build_chunk_storage(&cli, &backup_config, input_path)?;
+            let retention_policy = RetentionPolicy {
+                keep_last: *keep_last,
+                keep_hourly: *keep_hourly,
+                keep_daily: *keep_daily,
+                keep_weekly: *keep_weekly,
+                keep_monthly: *keep_monthly,
+                keep_yearly: *keep_yearly,
+                keep_within: *keep_within,
+            };
+
+            let mut forget_service =
+                ForgetService::new(backup_config.clone(), chunk_storage.clone());
+            if *prune {
+                forget_service.forget_and_prune(&retention_policy, *dry_run)?;
+            } else {
+                forget_service.forget(&retention_policy, *dry_run)?;
+            }
         }
         None => {}
     }
@@ -1,63 +1,129 @@
 use anyhow::Result;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
 use hoard_chunker::backup::models::backup_config::BackupConfig;
+use hoard_chunker::backup::models::backup_metadata::{BackupMetadata, SerializationType};
+use hoard_chunker::backup::models::backup_options::BackupOptions;
+use hoard_chunker::backup::models::chunk::Chunk;
+use hoard_chunker::backup::models::chunker_parameters::ChunkerParameters;
+use hoard_chunker::backup::models::compressibility::SkipReason;
+use hoard_chunker::backup::models::compression::Compression;
+use hoard_chunker::backup::models::key_file::KeyFile;
+use hoard_chunker::backup::models::lib::make_node;
+use hoard_chunker::backup::models::repository_config::{ChunkIdScheme, RepositoryConfig};
+use hoard_chunker::backup::models::repository_key::RepositoryKey;
+use hoard_chunker::backup::models::restore_options::RestoreOptions;
+use hoard_chunker::backup::models::retention_policy::RetentionPolicy;
+use hoard_chunker::backup::models::snapshot::Snapshot;
+use hoard_chunker::backup::models::storage_url::StorageUrl;
+use hoard_chunker::backup::models::zstd_dictionary::ZstdDictionary;
 use hoard_chunker::backup::services::backup_service::BackupService;
+use hoard_chunker::backup::services::check_service::{CheckReport, CheckService};
 use hoard_chunker::backup::services::chunk_reader_writer::ChunkReaderWriter;
 use hoard_chunker::backup::services::chunk_storage::{ChunkStorage, LocalChunkStorage};
+use hoard_chunker::backup::services::dictionary_service::DictionaryService;
 use hoard_chunker::backup::services::file_chunker::FileChunker;
+use hoard_chunker::backup::services::forget_service::ForgetService;
+use hoard_chunker::backup::services::prune_service::PruneService;
+use hoard_chunker::backup::services::redis_chunk_storage::RedisChunkStorage;
 use hoard_chunker::backup::services::restore_service::RestoreService;
 use hoard_chunker::DEFAULT_AVERAGE_SIZE;
+use itertools::Itertools;
 use log::{info, LevelFilter};
 use simplelog::{ColorChoice, CombinedLogger, Config, TermLogger, TerminalMode};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
 use std::sync::Arc;
-use std::{fs, path::Path};
+use std::time::{Duration, UNIX_EPOCH};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 use walkdir::WalkDir;
 
-#[test]
-fn test_backup_and_restore() -> Result<()> {
-    CombinedLogger::init(vec![TermLogger::new(
+fn init_logger() {
+    // every test tries to initialize the logger, only the first one succeeds
+    let _ = CombinedLogger::init(vec![TermLogger::new(
         LevelFilter::Debug,
         Config::default(),
         TerminalMode::Mixed,
         ColorChoice::Auto,
-    )])?;
-    let chunk_reader_writer = Arc::new(ChunkReaderWriter::new());
+    )]);
+}
 
-    let backup_input_path = "./tests/assets";
-    let backup_output_path = "./target/output";
-    let backup_config = Arc::new(BackupConfig::new(
-        DEFAULT_AVERAGE_SIZE,
-        backup_input_path.as_ref(),
-        backup_output_path.as_ref(),
+fn backup(input_path: &Path, output_path: &Path) -> Result<Snapshot> {
+    backup_with_average_size(input_path, output_path, DEFAULT_AVERAGE_SIZE)
+}
+
+fn backup_with_average_size(
+    input_path: &Path,
+    output_path: &Path,
+    average_size: u32,
+) -> Result<Snapshot> {
+    backup_with_config(BackupConfig::new(average_size, input_path, output_path))
+}
+
+fn backup_with_config(backup_config: BackupConfig) -> Result<Snapshot> {
+    backup_with_options(backup_config, BackupOptions::default())
+}
+
+fn backup_with_options(
+    backup_config: BackupConfig,
+    backup_options: BackupOptions,
+) -> Result<Snapshot> {
+    let backup_config = Arc::new(backup_config);
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(backup_config.clone(), Path::new(&backup_config.output_path))?,
     ));
-    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> =
-        Arc::new(Box::new(LocalChunkStorage::new(backup_config.clone())));
-    let backup_file_chunker = Arc::new(FileChunker::new(
+    let file_chunker = Arc::new(FileChunker::new(
         backup_config.clone(),
         chunk_storage.clone(),
     ));
     let mut backup_service = BackupService::new(
         backup_config.clone(),
-        backup_file_chunker.clone(),
-        chunk_storage.clone(),
+        file_chunker,
+        chunk_storage,
+        backup_options,
     );
-    backup_service.backup()?;
+    backup_service.backup()
+}
 
-    let restore_input_path = Path::new(backup_output_path);
-    let restore_output_path = Path::new("./target/restored");
-    let restore_config = Arc::new(BackupConfig::new(
-        DEFAULT_AVERAGE_SIZE,
-        &restore_input_path,
-        &restore_output_path,
-    ));
+fn restore(input_path: &Path, output_path: &Path, snapshot_id: Option<&str>) -> Result<()> {
+    restore_with_config(
+        BackupConfig::new(DEFAULT_AVERAGE_SIZE, input_path, output_path),
+        snapshot_id,
+    )
+}
+
+fn restore_with_config(restore_config: BackupConfig, snapshot_id: Option<&str>) -> Result<()> {
+    restore_with_options(restore_config, RestoreOptions::default(), snapshot_id)
+}
+
+fn restore_with_options(
+    restore_config: BackupConfig,
+    restore_options: RestoreOptions,
+    snapshot_id: Option<&str>,
+) -> Result<()> {
+    let restore_config = Arc::new(restore_config);
     let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> =
-        Arc::new(Box::new(LocalChunkStorage::new(restore_config.clone())));
+        Arc::new(Box::new(LocalChunkStorage::new(
+            restore_config.clone(),
+            Path::new(&restore_config.input_path),
+        )?));
+    let mut restore_service = RestoreService::new(restore_config, chunk_storage, restore_options);
+    restore_service.restore(snapshot_id)
+}
 
-    let mut restore_service = RestoreService::new(
-        restore_config.clone(),
-        chunk_storage.clone(),
-        chunk_reader_writer.clone(),
-    );
-    restore_service.restore()?;
+#[test]
+fn test_backup_and_restore() -> Result<()> {
+    init_logger();
+    let backup_input_path = Path::new("./tests/assets");
+    let backup_output_path = Path::new("./target/output");
+    backup(backup_input_path, backup_output_path)?;
+
+    let restore_output_path = Path::new("./target/restored");
+    restore(backup_output_path, restore_output_path, None)?;
 
     for entry in WalkDir::new(restore_output_path) {
         let dir_entry = entry?;
@@ -73,3 +139,1480 @@ fn test_backup_and_restore() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_restore_snapshot() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/snapshot_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    fs::write(input_path.join("file.txt"), "first version")?;
+    let first_snapshot = backup(&input_path, &output_path)?;
+    fs::write(input_path.join("file.txt"), "second version")?;
+    backup(&input_path, &output_path)?;
+
+    let restored_file_path = |restore_path: &Path| restore_path.join(input_path.join("file.txt"));
+
+    let first_restore_path = test_path.join("restored_first");
+    restore(
+        &output_path,
+        &first_restore_path,
+        Some(first_snapshot.short_id()),
+    )?;
+    assert_eq!(
+        fs::read_to_string(restored_file_path(&first_restore_path))?,
+        "first version"
+    );
+
+    let latest_restore_path = test_path.join("restored_latest");
+    restore(&output_path, &latest_restore_path, None)?;
+    assert_eq!(
+        fs::read_to_string(restored_file_path(&latest_restore_path))?,
+        "second version"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_repeated_chunks() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/repeated_chunks_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    let restore_path = test_path.join("restored");
+    fs::create_dir_all(&input_path)?;
+
+    // zero filled data is split into many identical chunks
+    let mut data = vec![0u8; 64 * 1024];
+    data.extend_from_slice(b"trailing data");
+    fs::write(input_path.join("zeros.bin"), &data)?;
+
+    backup_with_average_size(&input_path, &output_path, 4096)?;
+    restore(&output_path, &restore_path, None)?;
+
+    assert_eq!(
+        fs::read(restore_path.join(input_path.join("zeros.bin")))?,
+        data
+    );
+    Ok(())
+}
+
+#[test]
+fn test_prune_unreferenced_chunks() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/prune_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    fs::write(input_path.join("removed.txt"), "only in the first snapshot")?;
+    let first_snapshot = backup(&input_path, &output_path)?;
+    fs::remove_file(input_path.join("removed.txt"))?;
+    fs::write(input_path.join("kept.txt"), "in the second snapshot")?;
+    backup(&input_path, &output_path)?;
+
+    fs::remove_file(BackupMetadata::snapshot_path(
+        &output_path,
+        &first_snapshot.id,
+    ))?;
+
+    let prune_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &output_path,
+    ));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(prune_config.clone(), &output_path)?,
+    ));
+    let mut prune_service = PruneService::new(prune_config, chunk_storage.clone());
+
+    let dry_run_statistics = prune_service.prune(true)?;
+    assert_eq!(dry_run_statistics.removed_chunks, 1);
+    assert_eq!(chunk_storage.stored_chunks()?.len(), 2);
+
+    let prune_statistics = prune_service.prune(false)?;
+    assert_eq!(prune_statistics.removed_chunks, 1);
+    assert!(prune_statistics.reclaimed_bytes > 0);
+    assert_eq!(
+        prune_statistics.reclaimed_bytes,
+        dry_run_statistics.reclaimed_bytes
+    );
+    assert_eq!(chunk_storage.stored_chunks()?.len(), 1);
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    assert_eq!(
+        fs::read_to_string(restore_path.join(input_path.join("kept.txt")))?,
+        "in the second snapshot"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_forget_and_prune() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/forget_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    fs::write(input_path.join("file.txt"), "in the forgotten snapshot")?;
+    backup(&input_path, &output_path)?;
+    fs::write(input_path.join("file.txt"), "in the kept snapshot")?;
+    let kept_snapshot = backup(&input_path, &output_path)?;
+
+    let forget_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &output_path,
+    ));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(forget_config.clone(), &output_path)?,
+    ));
+    let mut forget_service = ForgetService::new(forget_config, chunk_storage.clone());
+    let retention_policy = RetentionPolicy {
+        keep_last: Some(1),
+        ..Default::default()
+    };
+
+    // a dry run reports what prune would delete without forgetting anything
+    let dry_run_statistics = forget_service.forget_and_prune(&retention_policy, true)?;
+    assert_eq!(dry_run_statistics.removed_chunks, 1);
+    assert_eq!(BackupMetadata::snapshot_ids(&output_path)?.len(), 2);
+    assert_eq!(chunk_storage.stored_chunks()?.len(), 2);
+
+    let prune_statistics = forget_service.forget_and_prune(&retention_policy, false)?;
+    assert_eq!(prune_statistics.removed_chunks, 1);
+    assert_eq!(
+        BackupMetadata::snapshot_ids(&output_path)?,
+        vec![kept_snapshot.id]
+    );
+    assert_eq!(chunk_storage.stored_chunks()?.len(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_latest_reads_only_snapshot_headers() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/latest_snapshot_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    fs::write(input_path.join("file.txt"), "in the first snapshot")?;
+    let first_snapshot = backup(&input_path, &output_path)?;
+    fs::write(input_path.join("file.txt"), "in the latest snapshot")?;
+    let latest_snapshot = backup(&input_path, &output_path)?;
+
+    // the files of older snapshots are not read
+    fs::write(
+        BackupMetadata::snapshot_path(&output_path, &first_snapshot.id),
+        "corrupt",
+    )?;
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert_eq!(backup_metadata.snapshot.id, latest_snapshot.id);
+
+    // snapshots that cannot be read are skipped
+    fs::remove_dir_all(output_path.join("snapshot_headers"))?;
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert_eq!(backup_metadata.snapshot.id, latest_snapshot.id);
+    assert!(BackupMetadata::snapshots(&output_path, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_check_repository() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/check_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("first.txt"), "first file")?;
+    fs::write(input_path.join("second.txt"), "second file")?;
+    backup(&input_path, &output_path)?;
+
+    let check_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &output_path,
+    ));
+    // every check reads the repository from disk again
+    let check = || -> Result<CheckReport> {
+        let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+            LocalChunkStorage::new(check_config.clone(), &output_path)?,
+        ));
+        CheckService::new(check_config.clone(), chunk_storage).check(Some(100.0))
+    };
+
+    let check_report = check()?;
+    assert!(check_report.is_ok());
+    assert_eq!(check_report.read_chunks, 2);
+
+    let chunk_storage = LocalChunkStorage::new(check_config.clone(), &output_path)?;
+    let mut stored_chunks = chunk_storage.stored_chunks()?;
+    stored_chunks.sort();
+    chunk_storage.delete_chunk(&stored_chunks[0])?;
+    chunk_storage.flush()?;
+
+    // flipped bytes inside a pack file
+    let pack_paths: Vec<PathBuf> = WalkDir::new(output_path.join("packs"))
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "pack")
+        })
+        .collect();
+    assert_eq!(pack_paths.len(), 1);
+    let pack_data: Vec<u8> = fs::read(&pack_paths[0])?
+        .into_iter()
+        .map(|byte| byte ^ 0xff)
+        .collect();
+    fs::write(&pack_paths[0], pack_data)?;
+
+    let check_report = check()?;
+    assert!(!check_report.is_ok());
+    assert_eq!(check_report.missing_chunks, vec![stored_chunks[0].clone()]);
+    assert_eq!(check_report.corrupt_chunks, vec![stored_chunks[1].clone()]);
+
+    // a tampered chunk in its own file, like repositories written before packs existed
+    let chunk_storage = LocalChunkStorage::new(check_config.clone(), &output_path)?;
+    chunk_storage.delete_chunk(&stored_chunks[1])?;
+    chunk_storage.flush()?;
+    ChunkReaderWriter::new(
+        &StorageUrl::from_path(&output_path)?,
+        None,
+        Compression::default(),
+    )?
+    .write_chunk(&stored_chunks[1], b"tampered", Path::new(""))?;
+
+    let check_report = check()?;
+    assert!(!check_report.is_ok());
+    assert_eq!(check_report.missing_chunks, vec![stored_chunks[0].clone()]);
+    assert_eq!(check_report.corrupt_chunks, vec![stored_chunks[1].clone()]);
+    Ok(())
+}
+
+#[test]
+fn test_encrypted_backup_and_restore() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/encryption_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("secret.txt"), "secret content")?;
+
+    KeyFile::create(&output_path, "password", &RepositoryKey::generate())?;
+    assert!(KeyFile::open(&output_path, "wrong password").is_err());
+    let repository_key = KeyFile::open(&output_path, "password")?;
+
+    let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path);
+    backup_config.repository_key = Some(repository_key.clone());
+    backup_config.chunk_id_scheme = ChunkIdScheme::KeyedBlake3;
+    let snapshot = backup_with_config(backup_config)?;
+
+    // neither the metadata nor the chunks are readable without the key
+    let snapshot_path = BackupMetadata::snapshot_path(&output_path, &snapshot.id);
+    assert!(BackupMetadata::deserialize(&output_path, &snapshot.id, None).is_err());
+    assert!(!String::from_utf8_lossy(&fs::read(&snapshot_path)?).contains("secret.txt"));
+
+    // chunk ids do not reveal the content hash
+    let mut repository_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &output_path);
+    repository_config.repository_key = Some(repository_key.clone());
+    let stored_chunk_ids =
+        LocalChunkStorage::new(Arc::new(repository_config), &output_path)?.stored_chunks()?;
+    assert_eq!(
+        stored_chunk_ids,
+        vec![Chunk::hash(
+            b"secret content",
+            Some(&repository_key.chunk_id_key())
+        )]
+    );
+    assert_ne!(stored_chunk_ids[0], Chunk::hash(b"secret content", None));
+
+    // encrypted files are bound to their name, a snapshot cannot be swapped for another
+    let swapped_snapshot_path = BackupMetadata::snapshot_path(&output_path, "swapped");
+    fs::copy(&snapshot_path, &swapped_snapshot_path)?;
+    assert!(BackupMetadata::deserialize(&output_path, "swapped", Some(&repository_key)).is_err());
+    fs::remove_file(swapped_snapshot_path)?;
+
+    let restore_path = test_path.join("restored");
+    let mut restore_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &restore_path);
+    restore_config.repository_key = Some(repository_key.clone());
+    restore_with_config(restore_config, None)?;
+    assert_eq!(
+        fs::read_to_string(restore_path.join(input_path.join("secret.txt")))?,
+        "secret content"
+    );
+
+    // restoring tampered chunks fails
+    let chunk_path = walkdir::WalkDir::new(&output_path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.path().parent().unwrap().file_name().unwrap().len() == 2)
+        .unwrap()
+        .into_path();
+    let mut chunk_data = fs::read(&chunk_path)?;
+    let last = chunk_data.len() - 1;
+    chunk_data[last] ^= 1;
+    fs::write(&chunk_path, chunk_data)?;
+
+    let mut restore_config = BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &test_path.join("restored_tampered"),
+    );
+    restore_config.repository_key = Some(repository_key);
+    assert!(restore_with_config(restore_config, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_restore_file_attributes() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/attributes_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let executable_path = input_path.join("script.sh");
+    fs::write(&executable_path, "#!/bin/sh")?;
+    fs::File::options()
+        .write(true)
+        .open(&executable_path)?
+        .set_modified(modified)?;
+    fs::set_permissions(&executable_path, fs::Permissions::from_mode(0o750))?;
+
+    let read_only_path = input_path.join("read_only.txt");
+    fs::write(&read_only_path, "read only")?;
+    fs::set_permissions(&read_only_path, fs::Permissions::from_mode(0o444))?;
+    backup(&input_path, &output_path)?;
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+
+    let restored_executable = fs::metadata(restore_path.join(&executable_path))?;
+    assert_eq!(restored_executable.permissions().mode() & 0o7777, 0o750);
+    assert_eq!(restored_executable.modified()?, modified);
+    assert_eq!(
+        restored_executable.uid(),
+        fs::metadata(&executable_path)?.uid()
+    );
+
+    let restored_read_only = fs::metadata(restore_path.join(&read_only_path))?;
+    assert_eq!(restored_read_only.permissions().mode() & 0o7777, 0o444);
+    assert_eq!(
+        restored_read_only.modified()?,
+        fs::metadata(&read_only_path)?.modified()?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_symlinks() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/symlinks_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    // absolute, so absolute link targets are backed up as well
+    let input_path = std::path::absolute(test_path.join("input"))?;
+    let output_path = test_path.join("output");
+    fs::create_dir_all(input_path.join("directory"))?;
+    fs::write(input_path.join("directory/file.txt"), "content")?;
+
+    let absolute_target = input_path.join("directory/file.txt");
+    unix::fs::symlink("directory/file.txt", input_path.join("relative"))?;
+    unix::fs::symlink("directory", input_path.join("linked_directory"))?;
+    unix::fs::symlink(&absolute_target, input_path.join("absolute"))?;
+    unix::fs::symlink("missing.txt", input_path.join("dangling"))?;
+    backup(&input_path, &output_path)?;
+
+    let restored_input_path =
+        |restore_path: &Path| restore_path.join(input_path.strip_prefix("/").unwrap());
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    for (link, target) in [
+        ("relative", Path::new("directory/file.txt")),
+        ("linked_directory", Path::new("directory")),
+        ("absolute", absolute_target.as_path()),
+        ("dangling", Path::new("missing.txt")),
+    ] {
+        assert_eq!(
+            fs::read_link(restored_input_path(&restore_path).join(link))?,
+            target
+        );
+    }
+    assert_eq!(
+        fs::read_to_string(restored_input_path(&restore_path).join("relative"))?,
+        "content"
+    );
+
+    // restoring over an earlier restore replaces the links
+    let rewritten_restore_path = test_path.join("rewritten");
+    for _ in 0..2 {
+        restore_with_options(
+            BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &rewritten_restore_path),
+            RestoreOptions {
+                rewrite_absolute_symlinks: true,
+                ..Default::default()
+            },
+            None,
+        )?;
+    }
+    let rewritten_target =
+        fs::read_link(restored_input_path(&rewritten_restore_path).join("absolute"))?;
+    assert_eq!(
+        rewritten_target,
+        std::path::absolute(restored_input_path(&rewritten_restore_path))?
+            .join("directory/file.txt")
+    );
+    assert_eq!(fs::read_to_string(rewritten_target)?, "content");
+    Ok(())
+}
+
+#[test]
+fn test_backup_linked_input_path() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/linked_input_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let output_path = test_path.join("output");
+    fs::create_dir_all(test_path.join("directory/nested"))?;
+    fs::write(test_path.join("directory/nested/file.txt"), "content")?;
+    let input_path = test_path.join("input");
+    unix::fs::symlink("directory", &input_path)?;
+    backup(&input_path, &output_path)?;
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    let restored_input_path = restore_path.join(&input_path);
+    assert!(fs::symlink_metadata(&restored_input_path)?.is_dir());
+    assert_eq!(
+        fs::read_to_string(restored_input_path.join("nested/file.txt"))?,
+        "content"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_rejects_paths_outside_of_output_path() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/restore_outside_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("file.txt"), "content")?;
+    let snapshot = backup(&input_path, &output_path)?;
+
+    // a snapshot whose file leads outside of the restore directory
+    let mut backup_metadata = BackupMetadata::deserialize(&output_path, &snapshot.id, None)?;
+    let mut file_metadata = backup_metadata
+        .file_metadata_map
+        .values()
+        .next()
+        .unwrap()
+        .clone();
+    file_metadata.path = "../../escaped.txt".to_string();
+    backup_metadata.file_metadata_map = [(file_metadata.key(), file_metadata)].into();
+    BackupMetadata::remove(&output_path, &snapshot.id)?;
+    backup_metadata.serialize(&output_path, SerializationType::MessagePack, None)?;
+
+    let restore_path = test_path.join("restored");
+    assert!(restore(&output_path, &restore_path, None).is_err());
+    assert!(!test_path.join("escaped.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn test_restore_directories() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/directories_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+    let empty_path = input_path.join("empty");
+    fs::create_dir_all(&empty_path)?;
+    fs::set_permissions(&empty_path, fs::Permissions::from_mode(0o700))?;
+
+    let directory_path = input_path.join("directory");
+    fs::create_dir_all(&directory_path)?;
+    fs::write(directory_path.join("file.txt"), "content")?;
+    fs::File::open(&directory_path)?.set_modified(modified)?;
+    fs::set_permissions(&directory_path, fs::Permissions::from_mode(0o555))?;
+    let backup_result = backup(&input_path, &output_path);
+    fs::set_permissions(&directory_path, fs::Permissions::from_mode(0o755))?;
+    backup_result?;
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+
+    let restored_empty = fs::metadata(restore_path.join(&empty_path))?;
+    assert!(restored_empty.is_dir());
+    assert_eq!(restored_empty.permissions().mode() & 0o7777, 0o700);
+
+    let restored_directory_path = restore_path.join(&directory_path);
+    let restored_directory = fs::metadata(&restored_directory_path)?;
+    assert_eq!(restored_directory.permissions().mode() & 0o7777, 0o555);
+    assert_eq!(restored_directory.modified()?, modified);
+    assert_eq!(
+        fs::read_to_string(restored_directory_path.join("file.txt"))?,
+        "content"
+    );
+
+    // allows removing the test directory on the next run
+    fs::set_permissions(&restored_directory_path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[test]
+fn test_restore_hardlinks() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/hardlinks_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(input_path.join("directory"))?;
+    fs::write(input_path.join("file.txt"), "content")?;
+    fs::hard_link(input_path.join("file.txt"), input_path.join("link.txt"))?;
+    fs::hard_link(
+        input_path.join("file.txt"),
+        input_path.join("directory/link.txt"),
+    )?;
+    fs::write(input_path.join("other.txt"), "other content")?;
+    backup(&input_path, &output_path)?;
+
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert_eq!(backup_metadata.file_metadata_map.len(), 2);
+    assert_eq!(backup_metadata.hardlink_groups.len(), 1);
+    assert_eq!(backup_metadata.hardlink_groups[0].links.len(), 2);
+
+    // restoring twice must not write through the links of the first restore
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    restore(&output_path, &restore_path, None)?;
+
+    let restored_input_path = restore_path.join(&input_path);
+    let inode = fs::metadata(restored_input_path.join("file.txt"))?.ino();
+    for link in ["link.txt", "directory/link.txt"] {
+        let restored_link = fs::metadata(restored_input_path.join(link))?;
+        assert_eq!(restored_link.ino(), inode);
+        assert_eq!(restored_link.nlink(), 3);
+        assert_eq!(
+            fs::read_to_string(restored_input_path.join(link))?,
+            "content"
+        );
+    }
+    assert_ne!(
+        fs::metadata(restored_input_path.join("other.txt"))?.ino(),
+        inode
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_sparse_file() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/sparse_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    // data between a leading and a trailing hole
+    let sparse_path = input_path.join("sparse.img");
+    let mut sparse_file = fs::File::create(&sparse_path)?;
+    sparse_file.set_len(64 * 1024 * 1024)?;
+    sparse_file.seek(SeekFrom::Start(16 * 1024 * 1024))?;
+    sparse_file.write_all(&[1u8; 64 * 1024])?;
+    drop(sparse_file);
+    let sparse_metadata = fs::metadata(&sparse_path)?;
+    let is_sparse = sparse_metadata.blocks() * 512 < sparse_metadata.len();
+
+    backup_with_average_size(&input_path, &output_path, 4096)?;
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    let file_metadata = backup_metadata
+        .file_metadata_map
+        .get(&sparse_path.display().to_string())
+        .unwrap();
+    if is_sparse {
+        assert_eq!(file_metadata.holes.len(), 2);
+        assert_eq!(file_metadata.holes[0].offset, 0);
+    }
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    let restored_path = restore_path.join(&sparse_path);
+    let restored_metadata = fs::metadata(&restored_path)?;
+    assert_eq!(restored_metadata.len(), sparse_metadata.len());
+    assert_eq!(fs::read(&restored_path)?, fs::read(&sparse_path)?);
+    if is_sparse {
+        assert!(restored_metadata.blocks() * 512 < restored_metadata.len() / 2);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_restore_extended_attributes() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/extended_attributes_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    for file_name in ["first.txt", "second.txt"] {
+        let file_path = input_path.join(file_name);
+        fs::write(&file_path, file_name)?;
+        if let Err(err) = xattr::set(&file_path, "user.label", b"shared") {
+            info!("Extended attributes are not supported, skipping: {}", err);
+            return Ok(());
+        }
+    }
+    backup(&input_path, &output_path)?;
+
+    // both files share one entry
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert_eq!(backup_metadata.extended_attributes.len(), 1);
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    for file_name in ["first.txt", "second.txt"] {
+        assert_eq!(
+            xattr::get(restore_path.join(&input_path).join(file_name), "user.label")?,
+            Some(b"shared".to_vec())
+        );
+    }
+
+    let skipped_restore_path = test_path.join("skipped");
+    restore_with_options(
+        BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &skipped_restore_path),
+        RestoreOptions {
+            restore_extended_attributes: false,
+            ..Default::default()
+        },
+        None,
+    )?;
+    assert_eq!(
+        xattr::get(
+            skipped_restore_path.join(&input_path).join("first.txt"),
+            "user.label"
+        )?,
+        None
+    );
+    Ok(())
+}
+
+#[test]
+fn test_restore_special_files() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/special_files_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    let fifo_path = input_path.join("fifo");
+    make_node(&fifo_path, libc::S_IFIFO | 0o640, 0, 0)?;
+    let _listener = UnixListener::bind(input_path.join("socket"))?;
+    // creating device nodes requires root
+    let device_path = input_path.join("null");
+    let has_device = make_node(&device_path, libc::S_IFCHR | 0o666, 1, 3).is_ok();
+    backup(&input_path, &output_path)?;
+
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert!(backup_metadata.file_metadata_map.is_empty());
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+
+    let restored_fifo = fs::symlink_metadata(restore_path.join(&fifo_path))?;
+    assert!(restored_fifo.file_type().is_fifo());
+    assert_eq!(restored_fifo.permissions().mode() & 0o7777, 0o640);
+    assert!(!restore_path.join(input_path.join("socket")).exists());
+    if has_device {
+        let restored_device = fs::symlink_metadata(restore_path.join(&device_path))?;
+        assert!(restored_device.file_type().is_char_device());
+        assert_eq!(restored_device.rdev(), fs::metadata(&device_path)?.rdev());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_backup_exclude_patterns() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/exclude_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    for directory in ["src", "node_modules/package", "cache", "project/build"] {
+        fs::create_dir_all(input_path.join(directory))?;
+    }
+    for file in [
+        "src/main.rs",
+        "src/main.tmp",
+        "src/keep.tmp",
+        "node_modules/package/index.js",
+        "cache/CACHEDIR.TAG",
+        "cache/data",
+        "project/build/output",
+        "project/notes.log",
+        "project/important.log",
+    ] {
+        fs::write(input_path.join(file), file)?;
+    }
+    fs::write(
+        input_path.join("project/.hoardignore"),
+        "build/\n*.log\n!important.log\n",
+    )?;
+    let exclude_file_path = test_path.join("excludes");
+    fs::write(&exclude_file_path, "# dependencies\nnode_modules\n")?;
+
+    backup_with_options(
+        BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path),
+        BackupOptions {
+            excludes: vec!["*.tmp".to_string()],
+            includes: vec!["keep.tmp".to_string()],
+            exclude_files: vec![exclude_file_path],
+            exclude_if_present: vec!["CACHEDIR.TAG".to_string()],
+            ..Default::default()
+        },
+    )?;
+
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    let backed_up_files: Vec<String> = backup_metadata
+        .file_metadata_map
+        .keys()
+        .map(|path| {
+            Path::new(path)
+                .strip_prefix(&input_path)
+                .unwrap()
+                .display()
+                .to_string()
+        })
+        .sorted()
+        .collect();
+    assert_eq!(
+        backed_up_files,
+        vec![
+            "project/.hoardignore",
+            "project/important.log",
+            "src/keep.tmp",
+            "src/main.rs"
+        ]
+    );
+
+    // excluded directories are not recorded either
+    let directories: Vec<&str> = backup_metadata
+        .directories
+        .iter()
+        .map(|directory| directory.path.as_str())
+        .collect();
+    assert!(!directories.contains(&input_path.join("cache").to_str().unwrap()));
+    assert!(!directories.contains(&input_path.join("node_modules").to_str().unwrap()));
+    Ok(())
+}
+
+#[test]
+fn test_backup_unchanged_files() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/unchanged_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    let file_path = input_path.join("file.bin");
+    let mut data = vec![0u8; 1024 * 1024];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+    fs::write(&file_path, &data)?;
+
+    // different chunk sizes produce different chunks, unless the chunks are reused
+    let file_chunks = |snapshot: &Snapshot| -> Result<Vec<String>> {
+        Ok(BackupMetadata::find(&output_path, &snapshot.id, None)?
+            .file_metadata_map
+            .get(&file_path.display().to_string())
+            .unwrap()
+            .chunks
+            .iter()
+            .map(|file_chunk| file_chunk.hash.clone())
+            .collect())
+    };
+    let backup_with = |average_size: u32, force_rehash: bool| {
+        backup_with_options(
+            BackupConfig::new(average_size, &input_path, &output_path),
+            BackupOptions {
+                force_rehash,
+                ..Default::default()
+            },
+        )
+    };
+
+    let first_snapshot = backup_with(4096, false)?;
+    let unchanged_snapshot = backup_with(16384, false)?;
+    assert_eq!(
+        file_chunks(&unchanged_snapshot)?,
+        file_chunks(&first_snapshot)?
+    );
+
+    let rehashed_snapshot = backup_with(16384, true)?;
+    assert_ne!(
+        file_chunks(&rehashed_snapshot)?,
+        file_chunks(&first_snapshot)?
+    );
+
+    // same size and modification time, but a new ctime
+    let modified = fs::metadata(&file_path)?.modified()?;
+    data[0] ^= 1;
+    fs::write(&file_path, &data)?;
+    fs::File::options()
+        .write(true)
+        .open(&file_path)?
+        .set_modified(modified)?;
+    let changed_snapshot = backup_with(4096, false)?;
+    assert_ne!(
+        file_chunks(&changed_snapshot)?,
+        file_chunks(&first_snapshot)?
+    );
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, Some(&unchanged_snapshot.id))?;
+    data[0] ^= 1;
+    assert_eq!(fs::read(restore_path.join(&file_path))?, data);
+    Ok(())
+}
+
+#[test]
+fn test_parallel_backup() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/parallel_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    let mut data = vec![0u8; 256 * 1024];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+    // the same chunks are found by several workers at once
+    for index in 0..32 {
+        fs::write(input_path.join(format!("copy_{}.bin", index)), &data)?;
+        fs::write(
+            input_path.join(format!("unique_{}.bin", index)),
+            index.to_string(),
+        )?;
+    }
+    // the chunks of one file are stored in parallel as well
+    let mut large_data = vec![0u8; 4 * 1024 * 1024];
+    blake3::Hasher::new()
+        .update(b"large")
+        .finalize_xof()
+        .fill(&mut large_data);
+    fs::write(input_path.join("large.bin"), &large_data)?;
+
+    let backup_config = Arc::new(BackupConfig::new(4096, &input_path, &output_path));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(backup_config.clone(), Path::new(&backup_config.output_path))?,
+    ));
+    let file_chunker = Arc::new(FileChunker::new(
+        backup_config.clone(),
+        chunk_storage.clone(),
+    ));
+    BackupService::new(
+        backup_config,
+        file_chunker,
+        chunk_storage.clone(),
+        BackupOptions {
+            workers: Some(8),
+            ..Default::default()
+        },
+    )
+    .backup()?;
+
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    assert_eq!(backup_metadata.file_metadata_map.len(), 65);
+    let referenced_chunks: Vec<&String> = backup_metadata
+        .file_metadata_map
+        .values()
+        .flat_map(|file_metadata| file_metadata.chunks.iter())
+        .map(|file_chunk| &file_chunk.hash)
+        .unique()
+        .sorted()
+        .collect();
+    let stored_chunks = chunk_storage.stored_chunks()?;
+    assert_eq!(
+        stored_chunks.iter().sorted().collect::<Vec<&String>>(),
+        referenced_chunks
+    );
+    assert_eq!(chunk_storage.chunk_map()?.len(), referenced_chunks.len());
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    for index in 0..32 {
+        assert_eq!(
+            fs::read(restore_path.join(input_path.join(format!("copy_{}.bin", index))))?,
+            data
+        );
+    }
+    assert_eq!(
+        fs::read(restore_path.join(input_path.join("large.bin")))?,
+        large_data
+    );
+    Ok(())
+}
+
+#[test]
+fn test_backup_chunker_parameters() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/chunker_parameters_test");
+    let _ = fs::remove_dir_all(test_path);
+
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+    let mut data = vec![0u8; 1024 * 1024];
+    blake3::Hasher::new().finalize_xof().fill(&mut data);
+    fs::write(input_path.join("file.bin"), &data)?;
+
+    backup_with_average_size(&input_path, &output_path, 8192)?;
+    assert_eq!(
+        RepositoryConfig::deserialize(&output_path)?.chunker_parameters,
+        Some(ChunkerParameters::new(8192))
+    );
+
+    // chunks are between the minimum and maximum size, only the last one may be smaller
+    let backup_metadata = BackupMetadata::latest(&output_path, None)?.unwrap();
+    let chunk_lengths: Vec<usize> = backup_metadata
+        .file_metadata_map
+        .values()
+        .flat_map(|file_metadata| file_metadata.chunks.iter())
+        .map(|file_chunk| file_chunk.length)
+        .collect();
+    assert!(chunk_lengths.len() > 1);
+    assert!(chunk_lengths.iter().all(|length| *length <= 4 * 8192));
+    assert!(chunk_lengths[..chunk_lengths.len() - 1]
+        .iter()
+        .all(|length| *length >= 8192 / 4));
+
+    // other parameters are not stored
+    backup_with_average_size(&input_path, &output_path, 16384)?;
+    assert_eq!(
+        RepositoryConfig::deserialize(&output_path)?.chunker_parameters,
+        Some(ChunkerParameters::new(8192))
+    );
+
+    // configs of older versions store no parameters, the next backup stores its own
+    let mut repository_config = RepositoryConfig::deserialize(&output_path)?;
+    repository_config.chunker_parameters = None;
+    repository_config.update(&output_path)?;
+    backup_with_average_size(&input_path, &output_path, 16384)?;
+    assert_eq!(
+        RepositoryConfig::deserialize(&output_path)?.chunker_parameters,
+        Some(ChunkerParameters::new(16384))
+    );
+
+    let mut backup_config = BackupConfig::new(8192, &input_path, &output_path);
+    backup_config.max_size = 4096;
+    assert!(backup_with_config(backup_config).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_open_repository_config() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/repository_config_test");
+    let _ = fs::remove_dir_all(test_path);
+    assert!(RepositoryConfig::open(test_path).is_err());
+
+    let repository_config =
+        RepositoryConfig::new(ChunkIdScheme::Blake3, ChunkerParameters::new(4096));
+    repository_config.serialize(test_path)?;
+    assert!(repository_config.serialize(test_path).is_err());
+
+    let opened_repository_config = RepositoryConfig::open(test_path)?;
+    assert_eq!(opened_repository_config.id, repository_config.id);
+    assert_eq!(opened_repository_config.version, RepositoryConfig::VERSION);
+    assert_eq!(
+        opened_repository_config.chunker_parameters,
+        Some(ChunkerParameters::new(4096))
+    );
+
+    // configs written before the format was versioned
+    fs::write(
+        RepositoryConfig::path(test_path),
+        r#"{"chunk_id_scheme":"keyed-blake3"}"#,
+    )?;
+    assert_eq!(RepositoryConfig::open(test_path)?.version, 1);
+
+    // older formats are read, and upgraded before anything is written to them
+    RepositoryConfig::upgrade(test_path)?;
+    let upgraded_repository_config = RepositoryConfig::open(test_path)?;
+    assert_eq!(
+        upgraded_repository_config.version,
+        RepositoryConfig::VERSION
+    );
+    assert_eq!(
+        upgraded_repository_config.chunk_id_scheme,
+        ChunkIdScheme::KeyedBlake3
+    );
+
+    // newer formats are rejected
+    fs::write(
+        RepositoryConfig::path(test_path),
+        format!(
+            r#"{{"chunk_id_scheme":"blake3","version":{}}}"#,
+            RepositoryConfig::VERSION + 1
+        ),
+    )?;
+    assert!(RepositoryConfig::open(test_path).is_err());
+    fs::write(
+        RepositoryConfig::path(test_path),
+        r#"{"chunk_id_scheme":"blake3","version":0}"#,
+    )?;
+    assert!(RepositoryConfig::open(test_path).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_backup_and_restore_with_storage_url() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/storage_url_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    let restore_path = test_path.join("restored");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("file.txt"), "stored in memory".repeat(1000))?;
+
+    // memory storage lives as long as its operator, so backup and restore share one chunk storage
+    let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path);
+    backup_config.storage_url = Some(StorageUrl::parse("memory://")?);
+    let backup_config = Arc::new(backup_config);
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(backup_config.clone(), Path::new(&backup_config.output_path))?,
+    ));
+    let file_chunker = Arc::new(FileChunker::new(
+        backup_config.clone(),
+        chunk_storage.clone(),
+    ));
+    BackupService::new(
+        backup_config.clone(),
+        file_chunker,
+        chunk_storage.clone(),
+        BackupOptions::default(),
+    )
+    .backup()?;
+
+    // only metadata is written to the repository directory
+    assert!(!chunk_storage.stored_chunks()?.is_empty());
+    assert!(ChunkReaderWriter::new(
+        &StorageUrl::from_path(&output_path)?,
+        None,
+        Compression::default(),
+    )?
+    .list_chunks(Path::new(""))?
+    .is_empty());
+
+    let mut restore_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &restore_path);
+    restore_config.storage_url = backup_config.storage_url.clone();
+    RestoreService::new(
+        Arc::new(restore_config),
+        chunk_storage,
+        RestoreOptions::default(),
+    )
+    .restore(None)?;
+
+    assert_eq!(
+        fs::read(restore_path.join(input_path.join("file.txt")))?,
+        fs::read(input_path.join("file.txt"))?
+    );
+    Ok(())
+}
+
+// requires a redis or dragonfly server, e.g. from compose-dev.yml:
+// REDIS_URL=redis://localhost:6379 cargo test -- --ignored
+#[test]
+#[ignore]
+fn test_redis_chunk_storage() -> Result<()> {
+    init_logger();
+    let redis_url = std::env::var("REDIS_URL").unwrap_or("redis://localhost:6379".to_string());
+    let test_path = Path::new("./target/redis_chunk_storage_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    let restore_path = test_path.join("restored");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("file.txt"), "shared index".repeat(1000))?;
+
+    let repository_config =
+        RepositoryConfig::new(ChunkIdScheme::Blake3, ChunkerParameters::new(4096));
+    repository_config.serialize(&output_path)?;
+    let backup_config = Arc::new(BackupConfig::new(4096, &input_path, &output_path));
+    let chunk_storage = RedisChunkStorage::new(
+        backup_config.clone(),
+        &output_path,
+        &redis_url,
+        &repository_config.id,
+    )?;
+    let other_chunk_storage = RedisChunkStorage::new(
+        backup_config.clone(),
+        &output_path,
+        &redis_url,
+        &repository_config.id,
+    )?;
+
+    // a claimed chunk is stored by one host only and published once its data is flushed
+    let chunk = Chunk {
+        hash: "0123456789abcdef".to_string(),
+        length: 16,
+    };
+    assert!(chunk_storage.add_chunk_if_not_exists(chunk.clone())?);
+    assert!(!chunk_storage.add_chunk_if_not_exists(chunk.clone())?);
+    assert!(!other_chunk_storage.add_chunk_if_not_exists(chunk.clone())?);
+    assert!(!other_chunk_storage.chunk_map()?.contains_key(&chunk.hash));
+    chunk_storage.flush()?;
+    assert!(other_chunk_storage.chunk_map()?.contains_key(&chunk.hash));
+    // waits for the chunks claimed by other hosts
+    other_chunk_storage.flush()?;
+    assert!(other_chunk_storage.added_chunks()?.is_empty());
+    chunk_storage.remove_chunk(&chunk.hash)?;
+
+    // the claim of a chunk that could not be stored is dropped
+    assert!(other_chunk_storage.add_chunk_if_not_exists(Chunk {
+        hash: "fedcba9876543210".to_string(),
+        length: 16,
+    })?);
+    other_chunk_storage.remove_chunk("fedcba9876543210")?;
+    assert!(!chunk_storage.chunk_exists("fedcba9876543210")?);
+
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(chunk_storage));
+    let file_chunker = Arc::new(FileChunker::new(
+        backup_config.clone(),
+        chunk_storage.clone(),
+    ));
+    BackupService::new(
+        backup_config.clone(),
+        file_chunker,
+        chunk_storage.clone(),
+        BackupOptions::default(),
+    )
+    .backup()?;
+    // the repository index is loaded into redis by the first backup only
+    assert!(other_chunk_storage.has_chunk_map()?);
+    assert!(!other_chunk_storage.chunk_map()?.is_empty());
+
+    let restore_config = Arc::new(BackupConfig::new(4096, &output_path, &restore_path));
+    let restore_chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> =
+        Arc::new(Box::new(RedisChunkStorage::new(
+            restore_config.clone(),
+            &output_path,
+            &redis_url,
+            &repository_config.id,
+        )?));
+    RestoreService::new(
+        restore_config,
+        restore_chunk_storage,
+        RestoreOptions::default(),
+    )
+    .restore(None)?;
+    assert_eq!(
+        fs::read(restore_path.join(input_path.join("file.txt")))?,
+        fs::read(input_path.join("file.txt"))?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_prune_repacks_packs() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/repack_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    let mut data = vec![0u8; 256 * 1024];
+    blake3::Hasher::new()
+        .update(b"repack")
+        .finalize_xof()
+        .fill(&mut data);
+    fs::write(input_path.join("kept.bin"), &data[..128 * 1024])?;
+    fs::write(input_path.join("removed.bin"), &data[128 * 1024..])?;
+    let first_snapshot = backup_with_average_size(&input_path, &output_path, 16 * 1024)?;
+    fs::remove_file(input_path.join("removed.bin"))?;
+    backup_with_average_size(&input_path, &output_path, 16 * 1024)?;
+
+    let packs = || -> Vec<PathBuf> {
+        WalkDir::new(output_path.join("packs"))
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.into_path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "pack")
+            })
+            .collect()
+    };
+    // both files were chunked into one pack, the second backup stored nothing new
+    assert_eq!(packs().len(), 1);
+
+    fs::remove_file(BackupMetadata::snapshot_path(
+        &output_path,
+        &first_snapshot.id,
+    ))?;
+    let prune_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &output_path,
+    ));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(prune_config.clone(), &output_path)?,
+    ));
+    let original_pack_size = fs::metadata(&packs()[0])?.len();
+
+    let prune_statistics = PruneService::new(prune_config, chunk_storage).prune(false)?;
+    assert!(prune_statistics.removed_chunks > 0);
+    assert_eq!(prune_statistics.repacked_packs, 1);
+    assert_eq!(packs().len(), 1);
+    assert_eq!(
+        prune_statistics.reclaimed_bytes,
+        original_pack_size - fs::metadata(&packs()[0])?.len()
+    );
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    assert_eq!(
+        fs::read(restore_path.join(input_path.join("kept.bin")))?,
+        &data[..128 * 1024]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_backup_with_mixed_compression() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/compression_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    fs::write(input_path.join("first.txt"), "zstd compressed".repeat(1000))?;
+    let first_snapshot = backup(&input_path, &output_path)?;
+
+    // chunks of every compression are read from one repository
+    for (index, compression) in [
+        Compression::None,
+        Compression::Lz4,
+        Compression::Brotli { level: 9 },
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        fs::write(
+            input_path.join(format!("{}.txt", compression)),
+            format!("{} compressed", compression).repeat(1000),
+        )?;
+        let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path);
+        backup_config.compression = compression;
+        backup_with_config(backup_config)?;
+        assert_eq!(BackupMetadata::list(&output_path, None)?.len(), index + 2);
+    }
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    for compression in ["none", "lz4", "brotli:9"] {
+        assert_eq!(
+            fs::read_to_string(restore_path.join(input_path.join(format!("{}.txt", compression))))?,
+            format!("{} compressed", compression).repeat(1000)
+        );
+    }
+
+    let first_restore_path = test_path.join("first_restored");
+    restore(&output_path, &first_restore_path, Some(&first_snapshot.id))?;
+    assert_eq!(
+        fs::read_to_string(first_restore_path.join(input_path.join("first.txt")))?,
+        "zstd compressed".repeat(1000)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_backup_skips_incompressible_data() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/incompressible_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    let mut random_data = vec![0; 4096];
+    OsRng.fill_bytes(&mut random_data);
+    let zstd_data = zstd::encode_all(&b"archived".repeat(1000)[..], 3)?;
+    fs::write(input_path.join("text.txt"), "compressible".repeat(1000))?;
+    fs::write(input_path.join("photo.jpg"), "not a photo".repeat(1000))?;
+    fs::write(input_path.join("archive"), &zstd_data)?;
+    fs::write(input_path.join("random.bin"), &random_data)?;
+
+    let backup_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &input_path,
+        &output_path,
+    ));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(backup_config.clone(), Path::new(&backup_config.output_path))?,
+    ));
+    let file_chunker = Arc::new(FileChunker::new(
+        backup_config.clone(),
+        chunk_storage.clone(),
+    ));
+    BackupService::new(
+        backup_config,
+        file_chunker.clone(),
+        chunk_storage,
+        BackupOptions::default(),
+    )
+    .backup()?;
+
+    let compression_statistics = file_chunker.compression_statistics();
+    assert_eq!(compression_statistics.count(None), 1);
+    for skip_reason in [
+        SkipReason::Extension,
+        SkipReason::Magic,
+        SkipReason::Entropy,
+    ] {
+        assert_eq!(compression_statistics.count(Some(skip_reason)), 1);
+    }
+
+    let restore_path = test_path.join("restored");
+    restore(&output_path, &restore_path, None)?;
+    let restored_input_path = restore_path.join(&input_path);
+    assert_eq!(
+        fs::read_to_string(restored_input_path.join("photo.jpg"))?,
+        "not a photo".repeat(1000)
+    );
+    assert_eq!(fs::read(restored_input_path.join("archive"))?, zstd_data);
+    assert_eq!(
+        fs::read(restored_input_path.join("random.bin"))?,
+        random_data
+    );
+    Ok(())
+}
+
+#[test]
+fn test_backup_with_trained_dictionary() -> Result<()> {
+    init_logger();
+    let test_path = Path::new("./target/dictionary_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    fs::create_dir_all(&input_path)?;
+
+    let user = |index: usize| {
+        format!(
+            r#"{{"id": {}, "name": "user {}", "email": "user{}@example.com", "roles": ["reader"]}}"#,
+            index, index, index
+        )
+    };
+    for index in 0..300 {
+        fs::write(input_path.join(format!("{}.json", index)), user(index))?;
+    }
+    backup(&input_path, &output_path)?;
+
+    let dictionary_config = Arc::new(BackupConfig::new(
+        DEFAULT_AVERAGE_SIZE,
+        &output_path,
+        &output_path,
+    ));
+    let chunk_storage: Arc<Box<dyn ChunkStorage + Send + Sync>> = Arc::new(Box::new(
+        LocalChunkStorage::new(dictionary_config.clone(), &output_path)?,
+    ));
+    let dictionary = DictionaryService::new(dictionary_config, chunk_storage).train(1000, 4096)?;
+    assert_eq!(dictionary.id, 1);
+    let repository_config = RepositoryConfig::open(&output_path)?;
+    assert_eq!(repository_config.dictionary_id, Some(1));
+    assert_eq!(repository_config.version, RepositoryConfig::VERSION);
+
+    // chunks of the second backup are compressed with the dictionary
+    for index in 300..400 {
+        fs::write(input_path.join(format!("{}.json", index)), user(index))?;
+    }
+    let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path);
+    backup_config.dictionaries = ZstdDictionary::list(&output_path, None)?;
+    backup_config.dictionary_id = Some(dictionary.id);
+    backup_with_config(backup_config)?;
+
+    let restore_path = test_path.join("restored");
+    assert!(restore(&output_path, &test_path.join("without_dictionary"), None).is_err());
+    let mut restore_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &restore_path);
+    restore_config.dictionaries = ZstdDictionary::list(&output_path, None)?;
+    restore_with_config(restore_config, None)?;
+    for index in [0, 299, 300, 399] {
+        assert_eq!(
+            fs::read_to_string(restore_path.join(input_path.join(format!("{}.json", index))))?,
+            user(index)
+        );
+    }
+    Ok(())
+}
+
+// docker compose -f compose-dev.yml up -d
+// AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin cargo test -- --ignored
+#[cfg(feature = "s3")]
+#[test]
+#[ignore]
+fn test_backup_and_restore_with_s3() -> Result<()> {
+    init_logger();
+    let storage_url = StorageUrl::parse(&std::env::var("S3_URL").unwrap_or(
+        "s3://hoard/s3_test?endpoint=http://localhost:9000&region=us-east-1".to_string(),
+    ))?;
+    let test_path = Path::new("./target/s3_test");
+    let _ = fs::remove_dir_all(test_path);
+    let input_path = test_path.join("input");
+    let output_path = test_path.join("output");
+    let restore_path = test_path.join("restored");
+    fs::create_dir_all(&input_path)?;
+    fs::write(input_path.join("file.txt"), "stored in s3".repeat(1000))?;
+
+    let mut backup_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &input_path, &output_path);
+    backup_config.storage_url = Some(storage_url.clone());
+    backup_with_config(backup_config)?;
+
+    // a new operator reads the chunks back from the bucket
+    let mut restore_config = BackupConfig::new(DEFAULT_AVERAGE_SIZE, &output_path, &restore_path);
+    restore_config.storage_url = Some(storage_url);
+    restore_with_config(restore_config, None)?;
+    assert_eq!(
+        fs::read_to_string(restore_path.join(input_path.join("file.txt")))?,
+        "stored in s3".repeat(1000)
+    );
+    Ok(())
+}